@@ -0,0 +1,71 @@
+use crate::cli::Cli;
+use crate::config::Config;
+use crate::output;
+use crate::session::OpenOpts;
+use crate::session::Session;
+use crate::sync;
+use crate::Direction;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+
+/// Upload `source` to `remote_path` on every host of `hosts` at once, each over
+/// its own session, then report how each went
+pub fn run(
+    cli: &Cli,
+    config: &Config,
+    open_opts: &OpenOpts,
+    source: &str,
+    hosts: &[String],
+    remote_path: Option<&str>,
+) -> Result<()> {
+    if cli.interactive || cli.pick {
+        bail!("--interactive and --pick don't work with several hosts");
+    }
+    sync::several_hosts();
+    let results: Vec<(&String, Result<()>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = hosts
+            .iter()
+            .map(|host| {
+                let handle = scope.spawn(move || {
+                    let session = sync::connect(cli, host, || {
+                        Session::connect(
+                            host.clone(),
+                            remote_path,
+                            source.to_owned(),
+                            Direction::Upload,
+                            open_opts,
+                            config,
+                        )
+                    })?;
+                    sync::run(cli, config, &session)
+                });
+                (host, handle)
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|(host, handle)| {
+                let result = handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("upload panicked")));
+                (host, result)
+            })
+            .collect()
+    });
+
+    let mut failed = 0;
+    for (host, result) in &results {
+        match result {
+            Ok(()) => output::field(host, "ok"),
+            Err(e) => {
+                output::field(host, &format!("failed: {e:#}"));
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} hosts failed", hosts.len());
+    }
+    Ok(())
+}
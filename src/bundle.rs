@@ -0,0 +1,126 @@
+use crate::git;
+use crate::remote;
+use anyhow::bail;
+use anyhow::Result;
+use log::info;
+use log::warn;
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// Bring the remote repo at `remote_dir` (`dest` through the mount) to the
+/// local HEAD by uploading a `git bundle`, cloning it when the remote repo is
+/// missing. Working-tree changes are left to the following transfer
+///
+/// LFS content is not fetched remotely, the working-tree transfer uploads it
+///
+/// A diverged remote repo is left untouched, and so is one with uncommitted
+/// changes unless `discard`
+pub fn sync_history(
+    remote_host: &str,
+    remote_dir: &Path,
+    source: &Path,
+    dest: &Path,
+    discard: bool,
+) -> Result<()> {
+    let local_head = git::head(source)?;
+
+    let out = remote::run(
+        remote_host,
+        &remote::git(remote_dir, &["rev-parse", "HEAD"]),
+        None,
+    )?;
+    let remote_head = if out.status.success() {
+        Some(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+    } else if dest.exists() {
+        info!("remote {remote_dir:?} exists but is not a git repo, skip bundle");
+        return Ok(());
+    } else {
+        None
+    };
+
+    if let Some(remote_head) = &remote_head {
+        if remote_head == &local_head {
+            return Ok(());
+        }
+        if !git::is_ancestor(source, remote_head) {
+            info!("remote HEAD {remote_head} is not an ancestor of local HEAD, skip bundle");
+            return Ok(());
+        }
+        // the checkout and hard reset below would lose them
+        if !discard {
+            let out = remote::run(
+                remote_host,
+                &remote::git(
+                    remote_dir,
+                    &[
+                        "status",
+                        "--porcelain",
+                        "--untracked-files=no",
+                        "--ignore-submodules=all",
+                    ],
+                ),
+                None,
+            )?;
+            if !out.status.success() || !out.stdout.is_empty() {
+                warn!(
+                    "remote working tree has uncommitted changes, skip bundle (--delete discards them): {}{}",
+                    String::from_utf8_lossy(&out.stdout).trim(),
+                    remote::stderr(&out)
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    let Some(name) = dest.file_name() else {
+        bail!("can't bundle into {remote_dir:?}");
+    };
+    let parent = dest.parent().unwrap();
+    std::fs::create_dir_all(parent)?;
+    let mut bundle_name = name.to_owned();
+    bundle_name.push(".smartscp-bundle");
+    let bundle = parent.join(&bundle_name);
+    let remote_bundle = remote_dir.with_file_name(&bundle_name);
+
+    info!("uploading git bundle to {bundle:?}");
+    git::create_bundle(source, &bundle, remote_head.as_deref())?;
+
+    let bundle_arg = remote_bundle.as_os_str();
+    let command = match remote_head {
+        Some(_) => remote::and([
+            remote::git(
+                remote_dir,
+                &[
+                    OsStr::new("fetch"),
+                    OsStr::new("-q"),
+                    bundle_arg,
+                    OsStr::new("HEAD"),
+                ],
+            ),
+            remote::git(remote_dir, &["checkout", "-q", "--", "."]),
+            remote::git(remote_dir, &["reset", "-q", "--hard", "FETCH_HEAD"]),
+        ]),
+        None => remote::and([
+            remote::shell([
+                OsStr::new("env"),
+                OsStr::new("GIT_LFS_SKIP_SMUDGE=1"),
+                OsStr::new("git"),
+                OsStr::new("clone"),
+                OsStr::new("-q"),
+                bundle_arg,
+                remote_dir.as_os_str(),
+            ]),
+            remote::git(remote_dir, &["remote", "remove", "origin"]),
+        ]),
+    };
+    let out = remote::run(remote_host, &command, None);
+    std::fs::remove_file(&bundle)?;
+    let out = out?;
+    if !out.status.success() {
+        bail!(
+            "fail to update remote repo from bundle: {}",
+            remote::stderr(&out)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use crate::mapped;
+use crate::remote;
+use crate::session::Session;
+use crate::state::State;
+use crate::transfer::join;
+use crate::walk;
+use crate::Direction;
+use anyhow::bail;
+use anyhow::Result;
+use sha2::Digest;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// How many files are hashed by one remote `sha256sum`
+const BATCH: usize = 512;
+
+/// SHA-256 of a local file, in hex
+pub fn file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    match mapped::map(path) {
+        Some(map) => hasher.update(&map[..]),
+        None => {
+            std::io::copy(&mut File::open(path)?, &mut hasher)?;
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hashes of the regular `files` under the local `root`
+///
+/// Files unchanged since the last sync reuse the hash recorded in `state`
+pub fn local(root: &Path, files: &[PathBuf], state: &State) -> Result<HashMap<PathBuf, String>> {
+    let mut hashes = HashMap::new();
+    for f in files {
+        let path = join(root, f);
+        let Ok(metadata) = walk::stat(&path) else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let cached = state
+            .get(f)
+            .filter(|r| r.matches(&metadata).unwrap_or(false))
+            .and_then(|r| r.hash.clone());
+        let hash = match cached {
+            Some(hash) => hash,
+            None => file(&path)?,
+        };
+        hashes.insert(f.clone(), hash);
+    }
+    Ok(hashes)
+}
+
+/// Hashes of the `files` under `root` on `remote_host`, computed remotely by
+/// batched `sha256sum` runs so no content crosses the network
+///
+/// Missing files are left out
+pub fn remote(
+    remote_host: &str,
+    root: &Path,
+    files: &[PathBuf],
+) -> Result<HashMap<PathBuf, String>> {
+    let mut hashes = HashMap::new();
+    for batch in files.chunks(BATCH) {
+        let paths: HashMap<OsString, &PathBuf> = batch
+            .iter()
+            .map(|f| (join(root, f).into_os_string(), f))
+            .collect();
+        let mut args = vec![OsStr::new("sha256sum"), OsStr::new("-z"), OsStr::new("--")];
+        args.extend(paths.keys().map(|p| p.as_os_str()));
+        let out = remote::run(remote_host, &remote::shell(args), None)?;
+        // sha256sum also fails for missing files, which are just left out
+        if out.status.code() == Some(127) {
+            bail!("fail to hash remote files: {}", remote::stderr(&out));
+        }
+        // records of `<hash>  <path>` (or `<hash> *<path>`), NUL terminated
+        for record in out.stdout.split(|b| *b == 0).filter(|r| r.len() > 66) {
+            let hash = String::from_utf8_lossy(&record[..64]).into_owned();
+            if let Some(f) = paths.get(OsStr::from_bytes(&record[66..])) {
+                hashes.insert((*f).clone(), hash);
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// `files` whose source and destination hashes differ, or which are missing
+/// on the destination. Files not hashed at all, like symlinks, are kept
+pub fn changed(
+    source: &HashMap<PathBuf, String>,
+    dest: &HashMap<PathBuf, String>,
+    files: &[PathBuf],
+) -> Vec<PathBuf> {
+    files
+        .iter()
+        .filter(|f| source.get(*f).is_none() || source.get(*f) != dest.get(*f))
+        .cloned()
+        .collect()
+}
+
+/// Hashes of `files` on the (source, destination) sides of `session`
+pub fn sides(
+    session: &Session,
+    files: &[PathBuf],
+    state: &State,
+) -> Result<(HashMap<PathBuf, String>, HashMap<PathBuf, String>)> {
+    let local_hashes = local(session.local_root(), files, state)?;
+    let remote_hashes = remote(&session.remote_host, &session.remote_root()?, files)?;
+    Ok(match session.direction {
+        Direction::Upload => (local_hashes, remote_hashes),
+        Direction::Download => (remote_hashes, local_hashes),
+    })
+}
+
+/// The copied `files` whose destination doesn't hash like their source,
+/// both sides being read again
+pub fn verify(session: &Session, files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let (source, dest) = sides(session, files, &State::default())?;
+    Ok(files
+        .iter()
+        .filter(|f| source.get(*f).is_some() && source.get(*f) != dest.get(*f))
+        .cloned()
+        .collect())
+}
@@ -0,0 +1,647 @@
+use crate::text::Eol;
+use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
+use clap_complete::Shell;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// A replacement of scp, but auto skip git-ignored files
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    // flags of $SMARTSCP_OPTS may be given again
+    args_override_self = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    /// `local_path` or `remote-host:path`
+    #[arg(required = true)]
+    pub source: Option<String>,
+
+    /// `remote-host`, `remote-host:path` or `local_path`
+    #[arg(required_unless_present_any = ["to", "hosts"])]
+    pub destination: Option<String>,
+
+    /// Upload to this remote host: `source` is a local path and the optional
+    /// `destination` a remote path, both taken literally even with ':'
+    #[arg(long, value_name = "REMOTE_HOST", conflicts_with = "from")]
+    pub to: Option<String>,
+
+    /// Upload to all these hosts at once, comma separated or a `@group` of the
+    /// config file; `destination` is then the optional remote path
+    #[arg(long, conflicts_with_all = ["to", "from"])]
+    pub hosts: Option<String>,
+
+    /// Download from this remote host: `source` is a remote path and
+    /// `destination` a local path, both taken literally even with ':'
+    #[arg(long, value_name = "REMOTE_HOST")]
+    pub from: Option<String>,
+
+    /// Upload exactly the files listed by `git ls-files` (tracked and staged),
+    /// skipping untracked files entirely
+    #[arg(long)]
+    pub tracked_only: bool,
+
+    /// With --tracked-only or --patch-mode, also delete remote files which were
+    /// deleted from the local working tree; --patch-mode then also discards
+    /// uncommitted changes of the remote working tree
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Never delete anything on the destination, an additive-only sync;
+    /// skipped deletions are listed with --verbose
+    #[arg(long, conflicts_with = "delete")]
+    pub no_delete: bool,
+
+    /// With --delete, move deleted remote files into
+    /// `~/.smartscp-trash/<timestamp>/` instead of removing them
+    #[arg(long, requires = "delete")]
+    pub trash: bool,
+
+    /// Apply `git diff HEAD` to the remote repo and copy only untracked files,
+    /// when the remote has the same HEAD; otherwise fall back to a file transfer
+    #[arg(long)]
+    pub patch_mode: bool,
+
+    /// When the remote repo is missing or behind, bring it to the local HEAD
+    /// with a `git bundle` before transferring the working tree; a remote repo
+    /// with uncommitted changes is left alone unless --delete discards them
+    #[arg(long)]
+    pub bundle: bool,
+
+    /// Abort when a destination file was modified after its source, i.e.
+    /// edited on the destination since the last sync
+    #[arg(long)]
+    pub check_conflicts: bool,
+
+    /// Copy every file, even those whose destination already has the same
+    /// size and mtime
+    #[arg(long)]
+    pub ignore_times: bool,
+
+    /// Compare SHA-256 hashes of both sides, computed remotely with
+    /// `sha256sum`, instead of size and mtime to skip unchanged files
+    #[arg(long, conflicts_with = "ignore_times")]
+    pub checksum: bool,
+
+    /// Hash both sides again after copying, copy mismatching files once more
+    /// and fail if they still differ
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Never overwrite existing destination files, skip and report them
+    #[arg(short = 'n', long)]
+    pub no_clobber: bool,
+
+    /// Ask before overwriting each existing destination file
+    #[arg(short, long, conflicts_with = "no_clobber")]
+    pub interactive: bool,
+
+    /// Start copying even when the destination seems to lack the free space
+    #[arg(long)]
+    pub no_space_check: bool,
+
+    /// Treat the destination as the exact target name, never as a directory
+    /// to copy into
+    #[arg(short = 'T', long)]
+    pub no_target_directory: bool,
+
+    /// Expand glob patterns (`*`, `?`, `[...]`, `{a,b}`) in a local source the
+    /// shell left alone; remote sources are always expanded
+    #[arg(long)]
+    pub glob: bool,
+
+    /// Skip files smaller than this, e.g. `4K` or `1M`
+    #[arg(long, value_parser = unbytify::unbytify)]
+    pub min_size: Option<u64>,
+
+    /// Skip files larger than this, e.g. `500M` or `10G`
+    #[arg(long, value_parser = unbytify::unbytify)]
+    pub max_size: Option<u64>,
+
+    /// Skip files whose destination is as new as the source or newer
+    #[arg(short, long)]
+    pub update: bool,
+
+    /// Only consider files modified within this long, e.g. `30m`, `12h` or `2d`;
+    /// a bare number counts days
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    pub newer_than: Option<Duration>,
+
+    /// Browse the source next to the destination and mark what to copy
+    #[arg(long)]
+    pub pick: bool,
+
+    /// When uploading, hardlink files unchanged since this earlier remote copy
+    /// instead of uploading them, for space-efficient snapshots; relative to
+    /// the destination, like rsync
+    #[arg(long, value_name = "PREVIOUS", conflicts_with_all = ["no_clobber", "interactive"])]
+    pub link_dest: Option<String>,
+
+    /// When uploading, send each distinct content once and copy duplicates
+    /// remotely from the first file having it
+    #[arg(long, conflicts_with_all = ["no_clobber", "interactive"])]
+    pub dedup: bool,
+
+    /// Recreate FIFOs on the destination; they are skipped by default, like
+    /// sockets and devices always are
+    #[arg(long)]
+    pub specials: bool,
+
+    /// Convert the line endings of text files to these, unless the
+    /// `.gitattributes` of the source says otherwise; converted files differ
+    /// in size and hash, so they are copied on every run and can't be verified
+    #[arg(long, value_name = "EOL", conflicts_with_all = ["checksum", "verify", "move_source"])]
+    pub text_mode: Option<Eol>,
+
+    /// Open this many sftp connections for the mount, so the requests of
+    /// parallel workers are in flight together on high-latency links
+    #[arg(long, value_name = "N")]
+    pub connections: Option<usize>,
+
+    /// Bytes per sftp read or write request, e.g. `255K` for servers whose
+    /// `limits@openssh.com` allow it; sshfs's default 32K suits any server
+    #[arg(long, value_name = "SIZE", value_parser = unbytify::unbytify)]
+    pub request_size: Option<u64>,
+
+    /// For large transfers, time a few small copies first, uploads in a
+    /// remote temporary directory, and go on with the fastest copy threads,
+    /// and block size of the `parblock` driver
+    #[arg(long)]
+    pub auto_tune: bool,
+
+    /// How local files are read (uploads) and written (downloads); `uring`
+    /// batches their syscalls through io_uring, on Linux 5.6 or later
+    #[arg(long, value_enum, default_value_t = LocalIo::Std)]
+    pub local_io: LocalIo,
+
+    /// Copy threads, 4 unless set in the config file
+    #[arg(long, value_name = "N")]
+    pub workers: Option<usize>,
+
+    /// Bytes per copy operation of the `parblock` driver, e.g. `4M`
+    #[arg(long, value_name = "SIZE", value_parser = unbytify::unbytify)]
+    pub block_size: Option<u64>,
+
+    /// The xcp driver: `parfile` copies files in parallel, `parblock` also
+    /// the blocks of each file
+    #[arg(long, value_parser = ["parfile", "parblock"])]
+    pub driver: Option<String>,
+
+    /// Whether to clone files with copy-on-write instead of copying them,
+    /// where the file system supports it
+    #[arg(long, value_enum, default_value_t = ReflinkMode::Auto)]
+    pub reflink: ReflinkMode,
+
+    /// Don't flush copied files to disk before finishing
+    #[arg(long)]
+    pub no_fsync: bool,
+
+    /// Don't copy permissions, and only warn when the server refuses to set
+    /// mtimes, like sftp-only chroots and object storage gateways
+    #[arg(long)]
+    pub no_perms: bool,
+
+    /// Fail with "transfer stalled" when no byte moved for this many seconds,
+    /// instead of hanging on a dead connection
+    #[arg(long, value_name = "SECONDS")]
+    pub stall_timeout: Option<u64>,
+
+    /// Give up on the whole run after this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+
+    /// Give up when copying a single file takes longer than this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub file_timeout: Option<u64>,
+
+    /// Wait for another run syncing the same directories instead of failing
+    #[arg(long)]
+    pub wait_lock: bool,
+
+    /// What copies the changed files: `xcp` through the sshfs mount, `rsync`
+    /// where both ends have it, or `scp` without any mount; `auto` picks `xcp`,
+    /// or `scp` where sshfs, FUSE or the remote sftp subsystem is missing
+    #[arg(long, value_enum, default_value_t = Backend::Auto)]
+    pub backend: Backend,
+
+    /// Remove each source file once its copy is checked by hash, or a symlink
+    /// once its copy points to the same target, like a `mv` across machines;
+    /// differing files are left in place
+    #[arg(long = "move", conflicts_with_all = ["patch_mode", "bundle"])]
+    pub move_source: bool,
+
+    /// Copy interrupted downloads, and the partial files of `--partial-dir`,
+    /// over from the start instead of appending to what is there
+    #[arg(long)]
+    pub no_resume: bool,
+
+    /// Write files into this directory next to their target, like
+    /// `.smartscp-partial`, and rename them into place once complete; a rerun
+    /// resumes from what an interrupted run left there
+    #[arg(long, value_name = "DIR", value_parser = parse_partial_dir)]
+    pub partial_dir: Option<PathBuf>,
+
+    /// Write the outcome of each file to this file at the end of the run: its
+    /// status, bytes, duration, retries and error; CSV for a `.csv` name,
+    /// json otherwise; one per host, like `report.web1.csv`, with several
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Write the metrics of the run to this file in the Prometheus text
+    /// format, for the node exporter textfile collector; one per host with
+    /// several
+    #[arg(long, value_name = "FILE")]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Push the metrics of the run to this Prometheus pushgateway, like
+    /// `http://pushgateway:9091`
+    #[arg(long, value_name = "URL")]
+    pub pushgateway: Option<String>,
+
+    /// The job the metrics are pushed under
+    #[arg(long, value_name = "NAME", default_value = "smartscp")]
+    pub metrics_job: String,
+
+    /// Print one tab separated line per event, `kind\tpath..`, in a format
+    /// kept stable for scripts
+    #[arg(long)]
+    pub porcelain: bool,
+
+    /// Color log messages, unless NO_COLOR is set with auto
+    #[arg(long, value_enum, default_value_t = clap::ColorChoice::Auto)]
+    pub color: clap::ColorChoice,
+
+    /// Where log messages go, the terminal by default; reports like
+    /// `deleted: ..` are still printed
+    #[arg(long, value_enum, default_value_t = LogTarget::Stderr)]
+    pub log_target: LogTarget,
+
+    /// The file log messages are appended to with --log-target file
+    #[arg(long, value_name = "FILE", required_if_eq("log_target", "file"))]
+    pub log_file: Option<PathBuf>,
+
+    /// Copy exactly the files listed in this file, one path relative to the
+    /// source per line, instead of walking the source; `-` reads stdin
+    #[arg(long, value_name = "FILE", conflicts_with = "tracked_only")]
+    pub files_from: Option<PathBuf>,
+
+    /// The list of --files-from is NUL separated, like the output of
+    /// `find -print0` or `git ls-files -z`
+    #[arg(short = '0', long, requires = "files_from")]
+    pub from0: bool,
+
+    /// Ordered rsync style `+ pattern` and `- pattern` rules, the first match
+    /// winning, and `: .rsync-filter` per-directory rule files
+    #[arg(long, value_name = "FILE")]
+    pub filter_file: Option<PathBuf>,
+
+    /// Copy what the default excludes skip, like `target/` and `node_modules/`;
+    /// they never apply to --files-from, --tracked-only and --patch-mode
+    #[arg(long)]
+    pub no_default_excludes: bool,
+
+    /// Skip dotfiles and everything inside dot directories
+    #[arg(long)]
+    pub no_hidden: bool,
+
+    /// Copy the targets of symlinks as regular files and directories instead
+    /// of the symlinks, like `scp -L`
+    #[arg(short = 'L', long)]
+    pub follow_symlinks: bool,
+
+    /// Skip the symlinks pointing outside the source, like into `/etc`,
+    /// listing them
+    #[arg(long, conflicts_with = "follow_symlinks")]
+    pub safe_links: bool,
+
+    /// Keep the owner and group of files and directories as raw uid and gid
+    /// numbers, for root to root backups
+    #[arg(long)]
+    pub numeric_ids: bool,
+
+    /// Clear these octal permission bits of copied files, like `022` to strip
+    /// group and other write on shared servers; downloads default to the local
+    /// umask
+    #[arg(long, value_name = "MASK", value_parser = parse_mask)]
+    pub perms_umask: Option<u32>,
+
+    /// Print more details, repeat for even more
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LogTarget {
+    Stderr,
+    File,
+    Syslog,
+    Journald,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// Whatever this machine and the remote support
+    Auto,
+    /// The xcp library, through the sshfs mount
+    Xcp,
+    /// `rsync` over ssh
+    Rsync,
+    /// The legacy scp protocol, downloads not skipping git-ignored files
+    Scp,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ReflinkMode {
+    /// Fail where cloning isn't supported
+    Always,
+    /// Clone where supported, copy elsewhere
+    Auto,
+    /// Always copy
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LocalIo {
+    /// Regular reads and writes
+    Std,
+    /// io_uring
+    Uring,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Show how the destination differs from the source, copying nothing
+    Diff {
+        /// `local_path` or `remote-host:path`
+        source: String,
+
+        /// `remote-host`, `remote-host:path` or `local_path`
+        destination: String,
+
+        /// Compare SHA-256 hashes instead of size and mtime
+        #[arg(long)]
+        checksum: bool,
+
+        /// Treat the destination as the exact target name
+        #[arg(short = 'T', long)]
+        no_target_directory: bool,
+    },
+
+    /// List a remote directory with sizes, permissions and mtimes
+    Ls {
+        /// `remote-host:path`
+        location: String,
+
+        /// Mark entries ignored by the remote `.gitignore`
+        #[arg(long)]
+        ignored: bool,
+    },
+
+    /// Delete a remote file or directory
+    Rm {
+        /// `remote-host:path`
+        location: String,
+
+        /// Remove directories and everything inside them
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Create a remote directory
+    Mkdir {
+        /// `remote-host:path`
+        location: String,
+
+        /// Also create missing parent directories, no error if it exists
+        #[arg(short, long)]
+        parents: bool,
+    },
+
+    /// Rename a remote file or directory
+    Mv {
+        /// `remote-host:path`
+        from: String,
+
+        /// `remote-host:path` on the same host, or an existing directory there
+        to: String,
+    },
+
+    /// Show the size of a remote tree per directory
+    Du {
+        /// `remote-host:path`
+        location: String,
+
+        /// Only report directories this many levels below `path`
+        #[arg(short = 'd', long)]
+        max_depth: Option<usize>,
+
+        /// Leave out files ignored by git
+        #[arg(long)]
+        gitignore: bool,
+    },
+
+    /// Hash a remote tree remotely with `sha256sum`, skipping git-ignored files
+    Checksum {
+        /// `remote-host:path`
+        location: String,
+
+        /// Compare with this local tree instead of printing the hashes
+        #[arg(long, value_name = "LOCAL_PATH")]
+        compare: Option<String>,
+    },
+
+    /// Record the hashes of a tree, or check a tree against them later
+    Manifest {
+        #[command(subcommand)]
+        action: ManifestAction,
+    },
+
+    /// Follow a growing remote file, like `tail -F`
+    Tail {
+        /// `remote-host:path`
+        location: String,
+
+        /// How many existing lines to print first
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: u64,
+    },
+
+    /// Keep hosts mounted and connected for later runs, which then skip the ssh
+    /// handshake, until interrupted
+    Daemon {
+        /// Hosts to connect up front, by default those of the config file;
+        /// others are connected when first asked for
+        hosts: Vec<String>,
+    },
+
+    /// Write a starter `~/.config/smartscp/config.toml`
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Print a shell completion script, completing ssh hosts too
+    Completions { shell: Shell },
+
+    /// Host aliases of `~/.ssh/config`, for completion scripts
+    #[command(name = "__hosts", hide = true)]
+    Hosts,
+
+    /// Remote entries completing a `remote-host:path`, for completion scripts
+    #[command(name = "__complete-remote", hide = true)]
+    CompleteRemote { partial: String },
+
+    /// Delete old trash left by `--trash` on a remote host
+    Purge {
+        remote_host: String,
+
+        /// Only purge the trash of runs older than this many days
+        #[arg(long, default_value_t = 30)]
+        older_than: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ManifestAction {
+    /// Write the sizes and SHA-256 hashes of all the files of a tree, ignored
+    /// ones included
+    Create {
+        /// `remote-host:path` or `local_path`
+        location: String,
+
+        /// The json file to write
+        manifest: PathBuf,
+    },
+
+    /// Check a tree against a manifest, listing what changed since
+    Verify {
+        /// The json file written by `manifest create`
+        manifest: PathBuf,
+
+        /// `remote-host:path` or `local_path`, by default the tree the manifest
+        /// was taken of
+        location: Option<String>,
+    },
+}
+
+/// The command line, with the whitespace separated default flags of
+/// `$SMARTSCP_OPTS` before those given for a copy, so the given ones win
+///
+/// Subcommands are parsed without them, they take none of the copy flags
+pub fn parse() -> Cli {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    if let Ok(cli) = Cli::try_parse_from(&args) {
+        if cli.command.is_some() {
+            return cli;
+        }
+    }
+    if let Some(opts) = std::env::var_os("SMARTSCP_OPTS") {
+        let defaults = opts.to_string_lossy().into_owned();
+        let defaults = defaults.split_whitespace().map(OsString::from);
+        args.splice(1..1, defaults);
+    }
+    Cli::parse_from(args)
+}
+
+/// Octal permission bits, like `022`
+fn parse_mask(mask: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mask, 8) {
+        Ok(mask) if mask <= 0o7777 => Ok(mask),
+        _ => Err(format!("invalid mask {mask:?}, expect octal like `022`")),
+    }
+}
+
+/// A directory relative to the target, on its filesystem so the rename into
+/// place works
+fn parse_partial_dir(dir: &str) -> Result<PathBuf, String> {
+    if dir.is_empty() || Path::new(dir).is_absolute() {
+        return Err(format!(
+            "invalid directory {dir:?}, expect one relative to the target like `.smartscp-partial`"
+        ));
+    }
+    Ok(PathBuf::from(dir))
+}
+
+/// A number of seconds, minutes, hours, days or weeks, like `90s` or `2d`
+fn parse_age(age: &str) -> Result<Duration, String> {
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (number, unit) = age.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid age {age:?}, expect e.g. `2d`"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" | "" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit {unit:?}, expect s, m, h, d or w")),
+    };
+    let too_old = || format!("age {age:?} is too large");
+    let duration = Duration::from_secs(number.checked_mul(seconds).ok_or_else(too_old)?);
+    SystemTime::now()
+        .checked_sub(duration)
+        .ok_or_else(too_old)?;
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_age("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_age("2d"), Ok(Duration::from_secs(2 * 86400)));
+        assert_eq!(parse_age("3"), Ok(Duration::from_secs(3 * 86400)));
+        assert_eq!(parse_age("1w"), Ok(Duration::from_secs(7 * 86400)));
+        for bad in [
+            "",
+            "d",
+            "2y",
+            "-1d",
+            "1.5d",
+            "99999999999999999999d",
+            "9999999999999999w",
+        ] {
+            assert!(parse_age(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_partial_dirs() {
+        assert_eq!(
+            parse_partial_dir(".smartscp-partial"),
+            Ok(PathBuf::from(".smartscp-partial"))
+        );
+        assert_eq!(parse_partial_dir("a/b"), Ok(PathBuf::from("a/b")));
+        for bad in ["", "/tmp/partial"] {
+            assert!(parse_partial_dir(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_masks() {
+        assert_eq!(parse_mask("022"), Ok(0o022));
+        assert_eq!(parse_mask("7777"), Ok(0o7777));
+        for bad in ["", "8", "-22", "10000", "u=rw"] {
+            assert!(parse_mask(bad).is_err(), "{bad}");
+        }
+    }
+}
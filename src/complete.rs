@@ -0,0 +1,132 @@
+use crate::cli::Cli;
+use crate::remote;
+use crate::session::split_remote;
+use anyhow::Result;
+use clap::CommandFactory;
+use clap_complete::Shell;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Print the completion script for `shell`, which also offers the hosts of
+/// `~/.ssh/config` and remote paths, looked up by `smartscp __hosts` and
+/// `smartscp __complete-remote` at completion time
+pub fn script(shell: Shell) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    clap_complete::generate(shell, &mut Cli::command(), "smartscp", &mut stdout);
+    let hosts = match shell {
+        Shell::Bash => {
+            r#"
+_smartscp_with_hosts() {
+    local cur
+    _get_comp_words_by_ref -n : cur
+    if [[ "$cur" == *:* ]]; then
+        COMPREPLY=($(smartscp __complete-remote "$cur" 2>/dev/null))
+        __ltrim_colon_completions "$cur"
+        compopt -o nospace
+        return
+    fi
+    _smartscp "$@"
+    COMPREPLY+=($(compgen -W "$(smartscp __hosts 2>/dev/null)" -- "$cur"))
+}
+complete -F _smartscp_with_hosts -o nosort -o bashdefault -o default smartscp
+"#
+        }
+        Shell::Zsh => {
+            r#"
+_smartscp_with_hosts() {
+    if [[ "$PREFIX" == *:* ]]; then
+        compadd -S '' -U -- ${(f)"$(smartscp __complete-remote "$PREFIX" 2>/dev/null)"}
+        return
+    fi
+    _smartscp "$@"
+    compadd -- ${(f)"$(smartscp __hosts 2>/dev/null)"}
+}
+compdef _smartscp_with_hosts smartscp
+"#
+        }
+        Shell::Fish => {
+            r#"
+complete -c smartscp -f -a "(smartscp __hosts 2>/dev/null)" -d "ssh host"
+complete -c smartscp -f -n "string match -q '*:*' -- (commandline -ct)" -a "(smartscp __complete-remote (commandline -ct) 2>/dev/null)"
+"#
+        }
+        _ => "",
+    };
+    stdout.write_all(hosts.as_bytes())?;
+    Ok(())
+}
+
+/// Host aliases of `~/.ssh/config`, leaving out patterns
+pub fn hosts() -> Result<Vec<String>> {
+    let config: PathBuf = [&std::env::var("HOME")?, ".ssh", "config"].iter().collect();
+    let Ok(config) = std::fs::read_to_string(config) else {
+        return Ok(Vec::new());
+    };
+    let mut hosts = Vec::new();
+    for line in config.lines() {
+        let line = line.trim();
+        let Some((keyword, value)) = line.split_once(|c: char| c.is_whitespace() || c == '=')
+        else {
+            continue;
+        };
+        if !keyword.eq_ignore_ascii_case("host") {
+            continue;
+        }
+        for host in value.split_whitespace() {
+            if !host.contains(['*', '?', '!']) && !hosts.iter().any(|h| h == host) {
+                hosts.push(host.to_owned());
+            }
+        }
+    }
+    Ok(hosts)
+}
+
+/// Print the remote entries starting like `partial`, a `remote-host:path`
+/// being typed, one `remote-host:path` per line with a `/` after directories
+pub fn remote(partial: &str) -> Result<()> {
+    let Some((remote_host, path)) = split_remote(partial) else {
+        return Ok(());
+    };
+    let (dir, prefix) = match path.rfind('/') {
+        Some(i) => path.split_at(i + 1),
+        None => ("", path),
+    };
+    // ssh starts in the home, and `~` isn't expanded inside quotes
+    let find_dir = match dir {
+        "" | "~/" => ".",
+        _ => dir.strip_prefix("~/").unwrap_or(dir),
+    };
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    let command = remote::shell([
+        "find",
+        find_dir,
+        "-mindepth",
+        "1",
+        "-maxdepth",
+        "1",
+        "-name",
+        pattern.as_str(),
+        "-printf",
+        "%f\\t%y\\n",
+    ]);
+    let out = remote::run_shared(remote_host, &command)?;
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        let Some((name, kind)) = line.rsplit_once('\t') else {
+            continue;
+        };
+        // like shells, hidden entries only when asked for
+        if name.starts_with('.') && !prefix.starts_with('.') {
+            continue;
+        }
+        let slash = if kind == "d" { "/" } else { "" };
+        println!("{remote_host}:{dir}{name}{slash}");
+    }
+    Ok(())
+}
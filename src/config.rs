@@ -0,0 +1,165 @@
+use crate::complete;
+use crate::output;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Defaults from `~/.config/smartscp/config.toml`, under the command line
+///
+/// ```toml
+/// workers = 8
+/// excludes = ["*.o"]
+///
+/// [hosts.build]
+/// remote_dir = "/data/me"
+/// identity = "~/.ssh/id_build"
+///
+/// [groups]
+/// web = ["web1", "web2"]
+/// ```
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// copy threads
+    pub workers: Option<usize>,
+    /// bytes per copy operation of the `parblock` driver
+    pub block_size: Option<u64>,
+    /// the xcp driver, `parfile` or `parblock`
+    pub driver: Option<String>,
+    /// parallel sftp connections of the mount
+    pub connections: Option<usize>,
+    /// bytes per sftp read or write request of the mount
+    pub request_size: Option<u64>,
+    /// gitignore-style patterns never copied
+    pub excludes: Vec<String>,
+    /// permission bits cleared on copied files, like `0o022`
+    pub perms_umask: Option<u32>,
+    /// replaces `DEFAULT_EXCLUDES`, `[]` copying everything
+    pub default_excludes: Option<Vec<String>>,
+    /// keyed by the host as written on the command line
+    pub hosts: HashMap<String, Profile>,
+    /// hosts uploaded to at once with `--hosts @group`
+    pub groups: HashMap<String, Vec<String>>,
+}
+
+/// Build output, dependencies, caches and editor droppings, skipped even
+/// outside git repos
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "target/",
+    "node_modules/",
+    "__pycache__/",
+    ".DS_Store",
+    "*.swp",
+    "*.swo",
+    "*~",
+    ".#*",
+];
+
+/// Settings of one remote host
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// where inferred remote paths start, instead of the remote home
+    pub remote_dir: Option<String>,
+    /// login name, unless given as `user@host`
+    pub user: Option<String>,
+    /// private key for ssh and sshfs
+    pub identity: Option<String>,
+    /// gitignore-style patterns never copied to or from this host
+    pub excludes: Vec<String>,
+    /// permission bits cleared on files copied to or from this host
+    pub perms_umask: Option<u32>,
+}
+
+/// `~/.config/smartscp/config.toml`, or under `$XDG_CONFIG_HOME` when set
+pub fn path() -> Result<PathBuf> {
+    let dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME")?).join(".config"),
+    };
+    Ok(dir.join("smartscp").join("config.toml"))
+}
+
+/// Ask a few questions and write a starter config file, listing the hosts of
+/// `~/.ssh/config` as commented out sections
+pub fn init(force: bool) -> Result<()> {
+    let path = path()?;
+    if path.exists() && !force {
+        bail!("{path:?} already exists, use --force to overwrite it");
+    }
+    let driver = ask("xcp driver, parfile or parblock", "parfile")?;
+    if driver != "parfile" && driver != "parblock" {
+        bail!("Unknown driver {driver:?}");
+    }
+    let workers: usize = ask("copy threads", "4")?
+        .parse()
+        .context("Expect a number of threads")?;
+    let excludes = ask("patterns never copied, separated by spaces", "")?;
+
+    let mut content = format!("workers = {workers}\ndriver = {driver:?}\n");
+    let excludes: Vec<&str> = excludes.split_whitespace().collect();
+    content.push_str(&format!("excludes = {excludes:?}\n"));
+    for host in complete::hosts()? {
+        content.push_str(&format!(
+            "\n# [hosts.{host:?}]\n# remote_dir = \"~\"\n# excludes = []\n"
+        ));
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, content).with_context(|| format!("Fail to write {path:?}"))?;
+    output::event("written", &[&path]);
+    Ok(())
+}
+
+/// Ask `question` on the terminal, an empty answer taking `default`
+fn ask(question: &str, default: &str) -> Result<String> {
+    eprint!("{question} [{default}]: ");
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let answer = line.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_owned())
+}
+
+impl Config {
+    /// The config file, or defaults when there is none
+    pub fn load() -> Result<Config> {
+        let path = path()?;
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                toml::from_str(&content).with_context(|| format!("Fail to parse {path:?}"))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e).with_context(|| format!("Fail to read {path:?}")),
+        }
+    }
+
+    /// `default_excludes`, or else `DEFAULT_EXCLUDES`
+    pub fn default_excludes(&self) -> Vec<String> {
+        match &self.default_excludes {
+            Some(defaults) => defaults.clone(),
+            None => DEFAULT_EXCLUDES.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    /// The hosts of `hosts`, comma separated or `@group`
+    pub fn hosts_of(&self, hosts: &str) -> Result<Vec<String>> {
+        match hosts.strip_prefix('@') {
+            Some(group) => match self.groups.get(group) {
+                Some(hosts) => Ok(hosts.clone()),
+                None => bail!("No group {group:?} in config"),
+            },
+            None => Ok(hosts.split(',').map(str::to_owned).collect()),
+        }
+    }
+
+    pub fn profile(&self, remote_host: &str) -> Profile {
+        self.hosts.get(remote_host).cloned().unwrap_or_default()
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::Config;
+use crate::mount::Mount;
+use crate::remote;
+use crate::state;
+use anyhow::Context;
+use anyhow::Result;
+use log::error;
+use log::info;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Where `smartscp daemon` listens
+fn socket_path() -> PathBuf {
+    state::cache_dir().join("daemon.sock")
+}
+
+/// A host kept connected by the daemon
+struct Warm {
+    mount: Mount,
+    master: Child,
+}
+
+impl Drop for Warm {
+    fn drop(&mut self) {
+        let _ = self.master.kill();
+        let _ = self.master.wait();
+    }
+}
+
+/// Keep a mount and a master ssh connection of each of `hosts`, by default
+/// the hosts of the config file, and of every host asked for later, until
+/// interrupted
+///
+/// Each request on the socket is a host name line, answered by the line of
+/// its mount directory, empty when it couldn't be mounted
+pub fn run(config: &Config, hosts: &[String]) -> Result<()> {
+    let socket = socket_path();
+    std::fs::create_dir_all(socket.parent().unwrap())?;
+    // left by a daemon which didn't exit cleanly
+    let _ = std::fs::remove_file(&socket);
+    let listener =
+        UnixListener::bind(&socket).with_context(|| format!("Fail to listen on {socket:?}"))?;
+
+    let warm = Hosts::default();
+    let hosts: Vec<String> = if hosts.is_empty() {
+        config
+            .hosts
+            .iter()
+            .map(|(host, profile)| match &profile.user {
+                Some(user) if !host.contains('@') => format!("{user}@{host}"),
+                _ => host.clone(),
+            })
+            .collect()
+    } else {
+        hosts.to_vec()
+    };
+    for host in &hosts {
+        warm_up(config, &warm, host);
+    }
+    info!("Listening on {socket:?}");
+
+    // a client waiting on a slow host holds up no other
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let warm = &warm;
+                    scope.spawn(move || serve(config, warm, stream));
+                }
+                Err(e) => error!("{e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The hosts of the daemon, each locked on its own while connecting
+type Hosts = Mutex<HashMap<String, Arc<Mutex<Option<Warm>>>>>;
+
+/// Answer the host line of `stream` with its mount directory
+fn serve(config: &Config, warm: &Hosts, mut stream: UnixStream) {
+    let mut host = String::new();
+    if BufReader::new(&stream).read_line(&mut host).is_err() {
+        return;
+    }
+    let dir = warm_up(config, warm, host.trim_end()).unwrap_or_default();
+    let mut answer = dir.into_os_string().into_string().unwrap_or_default();
+    answer.push('\n');
+    let _ = stream.write_all(answer.as_bytes());
+}
+
+/// The mount directory of `host`, connected first unless it still is
+fn warm_up(config: &Config, warm: &Hosts, host: &str) -> Option<PathBuf> {
+    let slot = warm
+        .lock()
+        .unwrap()
+        .entry(host.to_owned())
+        .or_default()
+        .clone();
+    let mut slot = slot.lock().unwrap();
+    // detached by the timeout of a run, or never mounted
+    if slot.as_ref().is_none_or(|w| !w.mount.alive()) {
+        *slot = None;
+        match connect(config, host) {
+            Ok(w) => *slot = Some(w),
+            Err(e) => error!("{host}: {e:#}"),
+        }
+    }
+    slot.as_ref().map(|w| w.mount.mounted(Path::new("/")))
+}
+
+/// Mount `host` and open its master connection
+fn connect(config: &Config, host: &str) -> Result<Warm> {
+    // the profile of `user@host` is the one of `host`
+    let profile = config.profile(host.rsplit('@').next().unwrap_or(host));
+    remote::clear_ssh_options(host);
+    if let Some(identity) = &profile.identity {
+        let identity = shellexpand::tilde(identity);
+        remote::add_ssh_option(Some(host), format!("IdentityFile={identity}"));
+    }
+    remote::keep_alive(host)?;
+    let mount = Mount::own(host)?;
+    let master = remote::master(host)?;
+    info!("{host} is connected");
+    Ok(Warm { mount, master })
+}
+
+/// The mount directory of `remote_host` kept by a running daemon
+pub fn lookup(remote_host: &str) -> Option<PathBuf> {
+    let mut stream = UnixStream::connect(socket_path()).ok()?;
+    stream
+        .write_all(format!("{remote_host}\n").as_bytes())
+        .ok()?;
+    let mut dir = String::new();
+    BufReader::new(stream).read_line(&mut dir).ok()?;
+    let dir = dir.trim_end();
+    if dir.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(dir))
+    }
+}
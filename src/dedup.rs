@@ -0,0 +1,92 @@
+use crate::checksum;
+use crate::remote;
+use crate::state::State;
+use crate::transfer::join;
+use anyhow::bail;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// How many duplicates are copied by one remote command
+const BATCH: usize = 256;
+
+/// A file with the same content as one uploaded before it
+pub struct Duplicate {
+    pub original: PathBuf,
+    pub file: PathBuf,
+}
+
+/// Split `files` (relative to the local `source`) into those to upload and
+/// those with the same content as one of them, by hashing them all
+///
+/// Files not hashed, like symlinks, are always uploaded
+pub fn split(
+    source: &Path,
+    files: Vec<PathBuf>,
+    state: &State,
+) -> Result<(Vec<PathBuf>, Vec<Duplicate>)> {
+    let hashes = checksum::local(source, &files, state)?;
+    let mut originals: HashMap<&String, &PathBuf> = HashMap::new();
+    let mut unique = Vec::new();
+    let mut duplicates = Vec::new();
+    for file in &files {
+        let Some(hash) = hashes.get(file) else {
+            unique.push(file.clone());
+            continue;
+        };
+        match originals.get(hash) {
+            Some(original) => duplicates.push(Duplicate {
+                original: (*original).clone(),
+                file: file.clone(),
+            }),
+            None => {
+                originals.insert(hash, file);
+                unique.push(file.clone());
+            }
+        }
+    }
+    Ok((unique, duplicates))
+}
+
+/// Create the `duplicates` under the remote `root` by copying their uploaded
+/// originals there, so their content never crosses the network again
+///
+/// They don't get the attributes of their own sources, see
+/// `transfer::copy_attributes`
+pub fn copy_remote(remote_host: &str, root: &Path, duplicates: &[Duplicate]) -> Result<()> {
+    for batch in duplicates.chunks(BATCH) {
+        let mut commands = Vec::new();
+        for duplicate in batch {
+            let to = join(root, &duplicate.file);
+            if let Some(parent) = to.parent() {
+                commands.push(remote::shell([
+                    OsStr::new("mkdir"),
+                    OsStr::new("-p"),
+                    OsStr::new("--"),
+                    parent.as_os_str(),
+                ]));
+            }
+            // replaced rather than written through, it may be a hardlink of
+            // --link-dest
+            commands.push(remote::shell([
+                OsStr::new("rm"),
+                OsStr::new("-f"),
+                OsStr::new("--"),
+                to.as_os_str(),
+            ]));
+            commands.push(remote::shell([
+                OsStr::new("cp"),
+                OsStr::new("--"),
+                join(root, &duplicate.original).as_os_str(),
+                to.as_os_str(),
+            ]));
+        }
+        let out = remote::run(remote_host, &remote::and(commands), None)?;
+        if !out.status.success() {
+            bail!("Fail to copy duplicates remotely: {}", remote::stderr(&out));
+        }
+    }
+    Ok(())
+}
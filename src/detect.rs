@@ -0,0 +1,60 @@
+use crate::remote;
+use anyhow::bail;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Whether `program` is an executable file in the local `PATH`
+pub fn found(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&path).any(|dir| {
+        dir.join(program)
+            .metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    })
+}
+
+/// Why this machine can't mount a remote with `sshfs`, or `None` when it can
+pub fn unmountable() -> Option<&'static str> {
+    if !found("sshfs") {
+        return Some("`sshfs` not found");
+    }
+    // Linux, then macFUSE
+    if !Path::new("/dev/fuse").exists() && !Path::new("/Library/Filesystems/macfuse.fs").exists() {
+        return Some("FUSE not available");
+    }
+    None
+}
+
+/// Which of `programs` are in the `PATH` of `remote_host`
+pub fn remote_programs<'a>(remote_host: &str, programs: &[&'a str]) -> Result<HashSet<&'a str>> {
+    let mut argv = vec![
+        "sh",
+        "-c",
+        r#"for p; do command -v "$p" >/dev/null && echo "$p"; done; true"#,
+    ];
+    // `$0` of the script
+    argv.push("sh");
+    argv.extend(programs);
+    let out = remote::run(
+        remote_host,
+        &remote::shell(argv.iter().map(OsStr::new)),
+        None,
+    )?;
+    if !out.status.success() {
+        bail!(
+            "Fail to look for {programs:?} on {remote_host}: {}",
+            remote::stderr(&out)
+        );
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    Ok(programs
+        .iter()
+        .copied()
+        .filter(|program| stdout.lines().any(|line| line == *program))
+        .collect())
+}
@@ -0,0 +1,87 @@
+use crate::checksum;
+use crate::output;
+use crate::session::Session;
+use crate::state::mtime_secs;
+use crate::state::State;
+use crate::transfer::join;
+use crate::walk;
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Print what differs between the source and the destination of `session`,
+/// copying nothing: `+` only in the source, `-` only in the destination, `M`
+/// different size and mtime (or hash, with `checksum`)
+pub fn run(session: &Session, checksum: bool) -> Result<()> {
+    let mut source_files = walk::files(&session.source)?;
+    let mut dest_files = if session.dest.exists() {
+        walk::files(&session.dest)?
+    } else {
+        Vec::new()
+    };
+    if let Some(pattern) = &session.pattern {
+        source_files = walk::matching(source_files, pattern)?;
+        dest_files = walk::matching(dest_files, pattern)?;
+    }
+    let dest_files: BTreeSet<PathBuf> = dest_files.into_iter().collect();
+    let all: Vec<PathBuf> = source_files.iter().chain(&dest_files).cloned().collect();
+    let (source_hashes, dest_hashes) = if checksum {
+        checksum::sides(session, &all, &State::default())?
+    } else {
+        (HashMap::new(), HashMap::new())
+    };
+
+    let mut differences = 0;
+    let source_set: BTreeSet<PathBuf> = source_files.into_iter().collect();
+    for file in &source_set {
+        let name = display_name(file);
+        if !dest_files.contains(file) {
+            output::row(
+                &format!("+ {}", name.display()),
+                &[OsStr::new("+"), name.as_os_str()],
+            );
+            differences += 1;
+            continue;
+        }
+        let modified = if checksum {
+            source_hashes.get(file) != dest_hashes.get(file)
+        } else {
+            let from = join(&session.source, file).symlink_metadata()?;
+            let to = join(&session.dest, file).symlink_metadata()?;
+            from.len() != to.len() || mtime_secs(&from)? != mtime_secs(&to)?
+        };
+        if modified {
+            output::row(
+                &format!("M {}", name.display()),
+                &[OsStr::new("M"), name.as_os_str()],
+            );
+            differences += 1;
+        }
+    }
+    for file in dest_files.difference(&source_set) {
+        let name = display_name(file);
+        output::row(
+            &format!("- {}", name.display()),
+            &[OsStr::new("-"), name.as_os_str()],
+        );
+        differences += 1;
+    }
+    let count = differences.to_string();
+    output::row(
+        &format!("{count} differences"),
+        &[OsStr::new("differences"), OsStr::new(&count)],
+    );
+    Ok(())
+}
+
+/// An empty path stands for the transferred file itself
+fn display_name(file: &Path) -> &Path {
+    if file.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        file
+    }
+}
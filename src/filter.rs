@@ -0,0 +1,244 @@
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use globset::GlobBuilder;
+use globset::GlobMatcher;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Ordered include and exclude rules of an rsync style filter file
+///
+/// ```text
+/// # the first matching rule wins, and directories are matched before the
+/// # files inside: the directory of a kept file must not be excluded
+/// + /src/generated/
+/// + /src/generated/keep.rs
+/// - /src/generated/*
+/// - *.tmp
+/// # per-directory rules, from the `.rsync-filter` of each directory
+/// : .rsync-filter
+/// ```
+///
+/// A leading `/` anchors a pattern to the source, or to the directory of its
+/// per-directory file, a trailing `/` makes it only match directories, and an
+/// excluded directory excludes everything inside
+pub struct Filter {
+    entries: Vec<Entry>,
+}
+
+enum Entry {
+    Rule(Rule),
+    /// the rules of the file of this name in each directory, the deeper
+    /// directories first
+    DirMerge(String),
+}
+
+struct Rule {
+    include: bool,
+    matcher: GlobMatcher,
+    dir_only: bool,
+}
+
+impl Filter {
+    pub fn load(path: &Path) -> Result<Filter> {
+        Ok(Filter {
+            entries: parse(path, true)?,
+        })
+    }
+
+    /// Those of `files` (relative to `source`) the rules don't exclude
+    pub fn apply(&self, source: &Path, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let mut merged: HashMap<PathBuf, Vec<Entry>> = HashMap::new();
+        let mut kept = Vec::new();
+        'files: for file in files {
+            if file.as_os_str().is_empty() {
+                kept.push(file);
+                continue;
+            }
+            // each directory on the way down, then the file itself
+            let mut candidates: Vec<&Path> = file
+                .ancestors()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect();
+            candidates.reverse();
+            for (i, candidate) in candidates.iter().enumerate() {
+                let is_dir = i + 1 < candidates.len();
+                if self.excluded(source, candidate, is_dir, &mut merged)? {
+                    continue 'files;
+                }
+            }
+            kept.push(file);
+        }
+        Ok(kept)
+    }
+
+    /// Whether the first rule matching `path` excludes it
+    fn excluded(
+        &self,
+        source: &Path,
+        path: &Path,
+        is_dir: bool,
+        merged: &mut HashMap<PathBuf, Vec<Entry>>,
+    ) -> Result<bool> {
+        for entry in &self.entries {
+            match entry {
+                Entry::Rule(rule) => {
+                    if let Some(include) = rule.verdict(path, is_dir) {
+                        return Ok(!include);
+                    }
+                }
+                Entry::DirMerge(name) => {
+                    let dirs = path.ancestors().skip(1);
+                    for dir in dirs {
+                        let merge_file = source.join(dir).join(name);
+                        if !merged.contains_key(&merge_file) {
+                            let entries = if merge_file.is_file() {
+                                parse(&merge_file, false)?
+                            } else {
+                                Vec::new()
+                            };
+                            merged.insert(merge_file.clone(), entries);
+                        }
+                        // anchored to the directory of the file
+                        let rel = path.strip_prefix(dir).unwrap_or(path);
+                        for entry in &merged[&merge_file] {
+                            if let Entry::Rule(rule) = entry {
+                                if let Some(include) = rule.verdict(rel, is_dir) {
+                                    return Ok(!include);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Rule {
+    /// Whether the rule includes `path` when it matches it
+    fn verdict(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        if (self.dir_only && !is_dir) || !self.matcher.is_match(path) {
+            return None;
+        }
+        Some(self.include)
+    }
+}
+
+/// The rules of the filter file `path`, with `.` merges read in place and, in
+/// the `top` file, `:` per-directory merges kept for later
+fn parse(path: &Path, top: bool) -> Result<Vec<Entry>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("Fail to read {path:?}"))?;
+    let mut entries = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (kind, arg) = line
+            .split_once(' ')
+            .with_context(|| format!("{path:?}:{}: expect `RULE PATTERN`", n + 1))?;
+        match kind {
+            "+" | "include" => entries.push(Entry::Rule(rule(true, arg)?)),
+            "-" | "exclude" => entries.push(Entry::Rule(rule(false, arg)?)),
+            "." | "merge" => {
+                let merged = path.parent().unwrap_or(Path::new(".")).join(arg);
+                entries.extend(parse(&merged, top)?);
+            }
+            ":" | "dir-merge" if top => entries.push(Entry::DirMerge(arg.to_owned())),
+            ":" | "dir-merge" => {
+                bail!(
+                    "{path:?}:{}: nested per-directory merges are not supported",
+                    n + 1
+                )
+            }
+            _ => bail!("{path:?}:{}: unknown rule {kind:?}", n + 1),
+        }
+    }
+    Ok(entries)
+}
+
+/// A rule of an rsync style `pattern`
+fn rule(include: bool, pattern: &str) -> Result<Rule> {
+    let dir_only = pattern.len() > 1 && pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    // unanchored patterns match the end of the path
+    let glob = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_owned(),
+        None => format!("**/{pattern}"),
+    };
+    let matcher = GlobBuilder::new(&glob)
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Bad filter pattern {pattern:?}"))?
+        .compile_matcher();
+    Ok(Rule {
+        include,
+        matcher,
+        dir_only,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kept(rules: &str, files: &[&str]) -> Vec<PathBuf> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter");
+        std::fs::write(&path, rules).unwrap();
+        let files = files.iter().map(PathBuf::from).collect();
+        Filter::load(&path)
+            .unwrap()
+            .apply(dir.path(), files)
+            .unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = "+ /keep.tmp\n- *.tmp\n";
+        let files = ["a.tmp", "dir/b.tmp", "keep.tmp", "dir/keep.tmp", "c.rs"];
+        assert_eq!(kept(rules, &files), ["keep.tmp", "c.rs"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn excluded_directories_exclude_their_contents() {
+        let rules = "- build/\n- /target\n";
+        let files = [
+            "build/out",
+            "src/build/out",
+            "build",
+            "target/debug/x",
+            "src/target",
+        ];
+        assert_eq!(
+            kept(rules, &files),
+            ["build", "src/target"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn included_directories_let_a_file_be_kept() {
+        let rules = "+ /src/generated/\n+ /src/generated/keep.rs\n- /src/generated/*\n";
+        let files = [
+            "src/generated/keep.rs",
+            "src/generated/other.rs",
+            "src/generated/sub/keep.rs",
+            "src/main.rs",
+        ];
+        assert_eq!(
+            kept(rules, &files),
+            ["src/generated/keep.rs", "src/main.rs"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter");
+        std::fs::write(&path, "? *.tmp\n").unwrap();
+        assert!(Filter::load(&path).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::walk;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Run `git -C dir args..` and return its stdout
+fn git<I, S>(dir: &Path, args: I) -> Result<Vec<u8>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("Fail to execute `git`, maybe `git` not found ?")?;
+    if !output.status.success() {
+        bail!(
+            "git failed in {:?}: {}",
+            dir,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Split the output of a `-z` git command into paths
+///
+/// The repo may be a remote one seen through the mount, whose index could name
+/// paths outside of it
+fn split_nul(bytes: &[u8]) -> Result<Vec<PathBuf>> {
+    let files: Vec<PathBuf> = bytes
+        .split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| PathBuf::from(OsStr::from_bytes(s)))
+        .collect();
+    walk::check_relative(&files)?;
+    Ok(files)
+}
+
+/// Files in the index under `dir` and its submodules, relative to `dir`
+///
+/// Files deleted from the working tree are still listed by git, but skipped here
+pub fn tracked_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let files = split_nul(&git(dir, ["ls-files", "-z", "--recurse-submodules"])?)?
+        .into_iter()
+        .filter(|f| dir.join(f).symlink_metadata().is_ok())
+        .collect();
+    Ok(files)
+}
+
+/// Files of HEAD deleted from the working tree, staged or not
+pub fn deleted_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    split_nul(&git(
+        dir,
+        [
+            "diff",
+            "HEAD",
+            "--relative",
+            "--name-only",
+            "-z",
+            "--diff-filter=D",
+        ],
+    )?)
+}
+
+/// The commit checked out in `dir`
+pub fn head(dir: &Path) -> Result<String> {
+    let out = git(dir, ["rev-parse", "HEAD"])?;
+    Ok(String::from_utf8_lossy(&out).trim().to_owned())
+}
+
+/// Binary diff of the working tree against HEAD, leaving out added files,
+/// submodules and, unless `deletions`, deleted files
+///
+/// Like the other diffs here, only what is under `dir`, but with paths relative
+/// to the top of the repo, which `git apply` in the matching remote directory
+/// expects; it would skip paths relative to `dir` as outside of it
+pub fn diff_head(dir: &Path, deletions: bool) -> Result<Vec<u8>> {
+    git(
+        dir,
+        [
+            "diff",
+            "HEAD",
+            "--binary",
+            "--no-color",
+            "--ignore-submodules=all",
+            if deletions {
+                "--diff-filter=a"
+            } else {
+                "--diff-filter=ad"
+            },
+            "--",
+            ".",
+        ],
+    )
+}
+
+/// Files added since HEAD, which `diff_head` leaves out
+pub fn added_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let files = split_nul(&git(
+        dir,
+        [
+            "diff",
+            "HEAD",
+            "--relative",
+            "--name-only",
+            "-z",
+            "--diff-filter=A",
+        ],
+    )?)?
+    .into_iter()
+    .filter(|f| dir.join(f).symlink_metadata().is_ok())
+    .collect();
+    Ok(files)
+}
+
+/// Untracked files which are not git-ignored
+pub fn untracked_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    split_nul(&git(
+        dir,
+        ["ls-files", "--others", "--exclude-standard", "-z"],
+    )?)
+}
+
+/// Whether `commit` is known locally and an ancestor of HEAD
+pub fn is_ancestor(dir: &Path, commit: &str) -> bool {
+    git(dir, ["merge-base", "--is-ancestor", commit, "HEAD"]).is_ok()
+}
+
+/// Write a bundle of HEAD to `file`; with `base`, only the commits since `base`
+pub fn create_bundle(dir: &Path, file: &Path, base: Option<&str>) -> Result<()> {
+    let mut args: Vec<&OsStr> = ["bundle", "create", "-q"].map(OsStr::new).to_vec();
+    args.push(file.as_os_str());
+    let range;
+    match base {
+        Some(base) => {
+            range = format!("{base}..HEAD");
+            args.push(OsStr::new(&range));
+        }
+        None => args.extend(["--branches", "--tags", "HEAD"].map(OsStr::new)),
+    }
+    git(dir, args)?;
+    Ok(())
+}
+
+/// Paths of the initialized submodules under `dir`, relative to it, not
+/// recursive
+pub fn submodules(dir: &Path) -> Result<Vec<PathBuf>> {
+    // unlike `$sm_path`, relative to `dir` rather than the top of the repo
+    let out = git(
+        dir,
+        ["submodule", "foreach", "--quiet", "echo \"$displaypath\""],
+    )?;
+    Ok(String::from_utf8_lossy(&out)
+        .lines()
+        .filter(|path| !path.starts_with("../"))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Files tracked through git LFS, i.e. with the `filter=lfs` attribute
+pub fn lfs_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let files = split_nul(&git(dir, ["ls-files", "-z", "--", ":(attr:filter=lfs)"])?)?
+        .into_iter()
+        .filter(|f| dir.join(f).symlink_metadata().is_ok())
+        .collect();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_nul_refuses_paths_outside_the_repo() {
+        assert_eq!(
+            split_nul(b"a/b\0.hidden\0").unwrap(),
+            [PathBuf::from("a/b"), PathBuf::from(".hidden")]
+        );
+        for hostile in [
+            &b"fine\0../../.bashrc\0"[..],
+            b"/etc/passwd\0",
+            b"a/../../b\0",
+        ] {
+            assert!(split_nul(hostile).is_err(), "{hostile:?}");
+        }
+    }
+
+    #[test]
+    fn diff_head_of_a_subdirectory_applies_in_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let (local, remote) = (dir.path().join("local"), dir.path().join("remote"));
+        std::fs::create_dir_all(local.join("sub")).unwrap();
+        std::fs::write(local.join("sub/file"), "old\n").unwrap();
+        std::fs::write(local.join("top"), "old\n").unwrap();
+        git(&local, ["init", "-q"]).unwrap();
+        git(&local, ["add", "."]).unwrap();
+        git(
+            &local,
+            [
+                "-c",
+                "user.name=t",
+                "-c",
+                "user.email=t@t",
+                "commit",
+                "-qm",
+                "init",
+            ],
+        )
+        .unwrap();
+        git(
+            dir.path(),
+            [
+                OsStr::new("clone"),
+                OsStr::new("-q"),
+                local.as_os_str(),
+                remote.as_os_str(),
+            ],
+        )
+        .unwrap();
+        std::fs::write(local.join("sub/file"), "new\n").unwrap();
+        std::fs::write(local.join("top"), "new\n").unwrap();
+
+        let patch = dir.path().join("patch");
+        std::fs::write(&patch, diff_head(&local.join("sub"), false).unwrap()).unwrap();
+        git(
+            &remote.join("sub"),
+            [OsStr::new("apply"), patch.as_os_str()],
+        )
+        .unwrap();
+        let read = |path: &str| std::fs::read_to_string(remote.join(path)).unwrap();
+        assert_eq!(read("sub/file"), "new\n");
+        assert_eq!(read("top"), "old\n");
+    }
+}
@@ -1,23 +1,71 @@
+mod broadcast;
+mod bundle;
+mod checksum;
+mod cli;
+mod complete;
+mod config;
+mod daemon;
+mod dedup;
+mod detect;
+mod diff;
+mod filter;
+mod git;
+mod manage;
+mod manifest;
+mod mapped;
+mod metrics;
+mod mount;
+mod output;
+mod patch;
+mod pick;
+mod progress;
+mod relay;
+mod remote;
+mod report;
+mod rsync;
+mod scp;
+mod session;
+mod space;
+mod sparse;
+mod special;
+mod state;
+mod stream;
+mod sync;
+mod text;
+mod transfer;
+mod trash;
+mod tune;
+mod uring;
+mod walk;
+
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
-use crossbeam_channel as cbc;
-use log::error;
+use cli::Backend;
+use cli::Commands;
+use cli::LogTarget;
+use cli::ManifestAction;
+use config::Config;
 use log::info;
+use log::warn;
+use log::LevelFilter;
 use pathdiff::diff_paths;
+use session::OpenOpts;
+use session::Session;
+use simplelog::ColorChoice;
+use simplelog::TermLogger;
+use simplelog::TerminalMode;
+use simplelog::WriteLogger;
 use ssh2_config::SshConfig;
 use ssh2_config::{HostParams, ParseRule};
 use std::collections::HashMap;
 use std::env;
+use std::fs::File;
 use std::io::BufReader;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Command;
-use std::sync::Arc;
-use xcp::drivers::load_driver;
-use xcp::errors::XcpError;
-use xcp::operations::StatSender;
-use xcp::operations::StatusUpdate;
+use systemd_journal_logger::JournalLog;
 
 #[derive(Debug)]
 pub enum PathProvenance {
@@ -54,15 +102,56 @@ pub struct Connection {
 }
 
 impl Connection {
-    fn new(remote_path: Option<&str>, local_path: &str, remote_home: Option<String>) -> Self {
-        let mut local_path_pf: PathBuf =
-            match shellexpand::full(local_path).map(|x| Path::new(x.as_ref()).canonicalize()) {
-                Ok(Ok(x)) => x,
-                _ => panic!("not a valid local path"),
-            };
-        if local_path_pf.is_relative() {
-            local_path_pf = env::current_dir().unwrap().join(local_path_pf);
-        }
+    /// Without `remote_path`, the remote path mirrors `local_path` relative to
+    /// the home, below `remote_dir` or else the remote home
+    ///
+    /// A download may name a local path yet to be created, whose missing
+    /// parents are allowed when the remote path is given, and created once
+    /// both sides are checked, by `Session::connect`
+    fn new(
+        remote_path: Option<&str>,
+        local_path: &str,
+        remote_home: Option<String>,
+        remote_dir: Option<String>,
+        direction: Direction,
+    ) -> Result<Self> {
+        let expanded = shellexpand::full(local_path)
+            .with_context(|| format!("Fail to expand {local_path:?}"))?;
+        let path = Path::new(expanded.as_ref());
+        let local_path_pf: PathBuf = match path.canonicalize() {
+            Ok(x) => x,
+            Err(_) if matches!(direction, Direction::Download) && !path.exists() => {
+                let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                    bail!("Fail to find the file name of {local_path:?}");
+                };
+                let parent = if parent.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    parent
+                };
+                // the closest existing directory, which ends a relative path
+                let existing = match remote_path {
+                    Some(_) => parent
+                        .ancestors()
+                        .find(|dir| dir.as_os_str().is_empty() || dir.exists())
+                        .unwrap_or(parent),
+                    None => parent,
+                };
+                // every ancestor is a prefix
+                let missing = parent.strip_prefix(existing).unwrap();
+                let existing = if existing.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    existing
+                };
+                existing
+                    .canonicalize()
+                    .with_context(|| format!("Fail to resolve {existing:?}"))?
+                    .join(missing)
+                    .join(name)
+            }
+            Err(e) => return Err(e).with_context(|| format!("Fail to resolve {local_path:?}")),
+        };
         let remote_path_pf = match remote_path {
             Some(x) => {
                 let pf =
@@ -71,7 +160,7 @@ impl Connection {
             }
             None => {
                 let pf = match diff_paths(&local_path_pf, std::env::var("HOME").unwrap()) {
-                    Some(x) => PathBuf::from(&remote_home.unwrap()).join(x),
+                    Some(x) => PathBuf::from(&remote_dir.or(remote_home).unwrap()).join(x),
                     None => panic!("don't support upload to remote path other than home"),
                 };
                 PathProvenance::Inferred(pf)
@@ -81,186 +170,284 @@ impl Connection {
         assert!(remote_path_pf.is_absolute());
         assert!(local_path_pf.is_absolute());
 
-        Connection {
+        Ok(Connection {
             remote_path: remote_path_pf,
             local_path: local_path_pf,
-        }
+        })
     }
 }
 
-enum Direction {
+#[derive(Clone, Copy)]
+pub enum Direction {
     Upload,
     Download,
 }
 
 fn main() -> Result<()> {
-    let mut arg_iter = env::args().skip(1);
-    let arg1: String = arg_iter.next().unwrap();
-    let arg2: String = arg_iter.next().unwrap();
-    if arg_iter.next().is_some() {
-        panic!("expect 2 arguments, meet more than 2");
+    let cli = cli::parse();
+    let log_level = match cli.verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    output::set_porcelain(cli.porcelain);
+    walk::follow_symlinks(cli.follow_symlinks);
+    if cli.follow_symlinks {
+        // remote symlinks are resolved by the sftp server
+        mount::add_sshfs_option("follow_symlinks".to_owned());
     }
-    let arg1_split = arg1.split_once(':');
-    let arg2_split = arg2.split_once(':');
-
-    let (remote_host, remote_path, local_path, direction) = match (arg1_split, arg2_split) {
-        (None, None) => {
-            // scp local_path remote-host
-            // ok
-            (arg2, None, arg1, Direction::Upload)
-        }
-        (None, Some((remote_host, remote_path))) => {
-            // scp local_path remote-host:remote-path
-            // ok
-            (
-                remote_host.to_owned(),
-                Some(remote_path),
-                arg1,
-                Direction::Upload,
-            )
+    // https://no-color.org
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let color = match cli.color {
+        clap::ColorChoice::Always => ColorChoice::Always,
+        clap::ColorChoice::Never => ColorChoice::Never,
+        clap::ColorChoice::Auto if no_color => ColorChoice::Never,
+        clap::ColorChoice::Auto => ColorChoice::Auto,
+    };
+    match cli.log_target {
+        LogTarget::Stderr => TermLogger::init(
+            log_level,
+            simplelog::Config::default(),
+            TerminalMode::Stderr,
+            color,
+        )?,
+        LogTarget::File => {
+            // required by clap with --log-target file
+            let path = cli.log_file.as_ref().unwrap();
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Fail to open {path:?}"))?;
+            WriteLogger::init(log_level, simplelog::Config::default(), file)?
         }
-        (Some((remote_host, remote_path)), None) => {
-            // scp remote-host:remote-path local_path
-            // ok
-            (
-                remote_host.to_owned(),
-                Some(remote_path),
-                arg2,
-                Direction::Download,
-            )
+        LogTarget::Syslog => {
+            let formatter = syslog::Formatter3164 {
+                facility: syslog::Facility::LOG_USER,
+                hostname: None,
+                process: "smartscp".to_owned(),
+                pid: std::process::id(),
+            };
+            let logger = syslog::unix(formatter).context("Fail to connect to syslog")?;
+            log::set_boxed_logger(Box::new(syslog::BasicLogger::new(logger)))?;
+            log::set_max_level(log_level);
         }
-        (Some(_), Some(_)) => {
-            unimplemented!("don't support filename contains :")
+        LogTarget::Journald => {
+            JournalLog::new()
+                .context("Fail to connect to journald")?
+                .with_syslog_identifier("smartscp".to_owned())
+                .install()?;
+            log::set_max_level(log_level);
         }
-    };
-
-    let host_params = get_remote_host(&remote_host).unwrap();
-
-    let mount = tempfile::tempdir()?;
-
-    let connection = Connection::new(
-        remote_path,
-        &local_path,
-        host_params.user.map(|u| format!("/home/{u}")),
-    );
-    let remote_path = mount
-        .path()
-        .join(diff_paths(&*connection.remote_path, "/").unwrap());
-
-    Command::new("sshfs")
-        .arg(format!("{remote_host}:/"))
-        .arg(mount.path())
-        .status()
-        .context("Fail to execute `sshfs`, maybe `sshfs` not found ?")?;
-
-    let opts = Arc::new(xcp::options::Opts {
-        gitignore: true,
-        recursive: true,
-        fsync: true,
-        verbose: 0,
-        workers: 4,
-        block_size: 1048576,
-        no_clobber: false,
-        glob: false,
-        no_progress: false,
-        no_perms: false,
-        driver: xcp::drivers::Drivers::ParFile,
-        no_target_directory: false,
-        reflink: xcp::operations::Reflink::Auto,
-        paths: vec![],
-    });
+    }
 
-    match direction {
-        Direction::Upload => {
-            println!("local: {:?}", connection.local_path);
-            println!("remote: {:?}", connection.remote_path.deref());
+    // these need no config file, and `init` replaces a broken one
+    match &cli.command {
+        Some(Commands::Purge {
+            remote_host,
+            older_than,
+        }) => return trash::purge(remote_host, *older_than),
+        Some(Commands::Init { force }) => return config::init(*force),
+        Some(Commands::Completions { shell }) => return complete::script(*shell),
+        Some(Commands::Hosts) => {
+            for host in complete::hosts()? {
+                println!("{host}");
+            }
+            return Ok(());
         }
-        Direction::Download => {
-            println!("remote: {:?}", connection.remote_path.deref());
-            println!("local: {:?}", connection.local_path);
+        Some(Commands::CompleteRemote { partial }) => return complete::remote(partial),
+        Some(Commands::Tail { location, lines }) => {
+            let Some((remote_host, remote_path)) = session::split_remote(location) else {
+                bail!("Only `remote-host:file` can be followed");
+            };
+            return stream::tail(remote_host, remote_path, *lines);
         }
+        _ => {}
     }
 
-    let (source, dest): (PathBuf, PathBuf) = match direction {
-        Direction::Upload => (connection.local_path, remote_path),
-        Direction::Download => (remote_path, connection.local_path),
-    };
-
-    let pb = xcp::progress::create_bar(&opts, 0)?;
-    let (stat_tx, stat_rx) = cbc::unbounded();
-    let stats = StatSender::new(stat_tx, &opts);
-
-    let driver = load_driver(&opts)?;
-
-    if dest.is_file() {
-        // Special case; attemping to rename/overwrite existing file.
-        if opts.no_clobber {
-            return Err(XcpError::DestinationExists(
-                "Destination file exists and --no-clobber is set.",
-                dest,
-            )
-            .into());
-        }
+    let mut config = Config::load()?;
+    if cli.no_default_excludes {
+        config.default_excludes = Some(Vec::new());
+    }
+    if let Some(connections) = cli.connections.or(config.connections) {
+        mount::add_sshfs_option(format!("max_conns={connections}"));
+    }
+    if let Some(size) = cli.request_size.or(config.request_size) {
+        mount::add_sshfs_option(format!("max_read={size}"));
+        mount::add_sshfs_option(format!("max_write={size}"));
+    }
 
-        /*
-        // Special case: Attempt to overwrite a file with
-        // itself. Always disallow for now.
-        if is_same_file(&source, &dest)? {
-            return Err(XcpError::DestinationExists(
-                "Source and destination is the same file.",
-                dest,
-            )
-            .into());
+    match &cli.command {
+        Some(Commands::Diff {
+            source,
+            destination,
+            checksum,
+            no_target_directory,
+        }) => {
+            let open_opts = OpenOpts {
+                no_target_directory: *no_target_directory,
+                ..Default::default()
+            };
+            let session = Session::open(source.clone(), destination.clone(), &open_opts, &config)?;
+            return diff::run(&session, *checksum);
         }
-         */
-
-        info!("Copying file {:?} to {:?}", source, dest);
-        driver.copy_single(&source, &dest, stats)?;
-    } else {
-        // Sanity-check all sources up-front
-        info!("Copying source {:?} to {:?}", source, dest);
-        if !source.exists() {
-            return Err(XcpError::InvalidSource("Source does not exist.").into());
+        Some(Commands::Ls { location, ignored }) => return manage::ls(location, *ignored),
+        Some(Commands::Rm {
+            location,
+            recursive,
+            force,
+        }) => return manage::rm(location, *recursive, *force),
+        Some(Commands::Mkdir { location, parents }) => return manage::mkdir(location, *parents),
+        Some(Commands::Mv { from, to }) => return manage::mv(from, to),
+        Some(Commands::Du {
+            location,
+            max_depth,
+            gitignore,
+        }) => return manage::du(location, *max_depth, *gitignore),
+        Some(Commands::Checksum { location, compare }) => {
+            return manage::checksum(location, compare.as_deref())
         }
-
-        if source.is_dir() && !opts.recursive {
-            return Err(XcpError::InvalidSource(
-                "Source is directory and --recursive not specified.",
-            )
-            .into());
+        Some(Commands::Daemon { hosts }) => return daemon::run(&config, hosts),
+        Some(Commands::Manifest { action }) => {
+            return match action {
+                ManifestAction::Create { location, manifest } => {
+                    manifest::create(location, manifest)
+                }
+                ManifestAction::Verify { manifest, location } => {
+                    manifest::verify(manifest, location.as_deref())
+                }
+            }
         }
-
-        if &source == &dest {
-            return Err(XcpError::InvalidSource("Cannot copy a directory into itself").into());
+        _ => {}
+    }
+    // the source is required when no subcommand is given, so is the
+    // destination unless uploading with --to
+    let source = cli.source.clone().unwrap();
+    if source == "-" {
+        let destination = cli.destination.as_deref().unwrap_or_default();
+        let (remote_host, remote_path) = match &cli.to {
+            Some(remote_host) => (remote_host.as_str(), destination),
+            None => match session::split_remote(destination) {
+                Some(x) => x,
+                None => bail!("Stdin can only be uploaded to `remote-host:file`"),
+            },
+        };
+        return stream::upload(remote_host, remote_path);
+    }
+    if cli.destination.as_deref() == Some("-") {
+        let (remote_host, remote_path) = match &cli.from {
+            Some(remote_host) => (remote_host.as_str(), source.as_str()),
+            None => match session::split_remote(&source) {
+                Some(x) => x,
+                None => bail!("Only `remote-host:file` can be downloaded to stdout"),
+            },
+        };
+        return stream::download(remote_host, remote_path);
+    }
+    let open_opts = OpenOpts {
+        no_target_directory: cli.no_target_directory,
+        glob: cli.glob,
+        inferred_is_target: cli.patch_mode,
+    };
+    // uploads to several hosts, `--hosts` or `host1,host2:path`
+    let broadcast = match (&cli.hosts, cli.destination.as_deref()) {
+        (Some(hosts), remote_path) => Some((config.hosts_of(hosts)?, remote_path)),
+        (None, Some(destination))
+            if cli.to.is_none()
+                && cli.from.is_none()
+                && session::split_remote(&source).is_none() =>
+        {
+            match session::split_remote(destination) {
+                Some((hosts, remote_path)) if hosts.contains(',') => {
+                    Some((config.hosts_of(hosts)?, Some(remote_path)))
+                }
+                None if destination.contains(',') => Some((config.hosts_of(destination)?, None)),
+                _ => None,
+            }
         }
-
-        if dest.exists() && !dest.is_dir() {
-            return Err(XcpError::InvalidDestination(
-                "Source is directory but target exists and is not a directory",
-            )
-            .into());
+        _ => None,
+    };
+    if let Some((hosts, remote_path)) = broadcast {
+        return broadcast::run(&cli, &config, &open_opts, &source, &hosts, remote_path);
+    }
+    // between two remote hosts, through this machine
+    if let (None, None, Some(destination)) = (&cli.to, &cli.from, &cli.destination) {
+        if session::split_remote(&source).is_some() && session::split_remote(destination).is_some()
+        {
+            return relay::run(&cli, &config, &open_opts, &source, destination);
         }
-
-        driver.copy_all(vec![source], &dest, stats)?;
     }
-
-    // Gather the results as we go; our end of the channel has been
-    // moved to the driver call and will end when drained.
-    for stat in stat_rx {
-        match stat {
-            StatusUpdate::Copied(v) => pb.inc(v),
-            StatusUpdate::Size(v) => pb.inc_size(v),
-            StatusUpdate::Error(e) => {
-                // FIXME: Optional continue?
-                error!("Received error: {}", e);
-                return Err(e.into());
+    let (remote_host, remote_path, local_path, direction) = match (&cli.to, &cli.from) {
+        (Some(remote_host), _) => (
+            remote_host.clone(),
+            cli.destination.clone(),
+            source,
+            Direction::Upload,
+        ),
+        (None, Some(remote_host)) => (
+            remote_host.clone(),
+            Some(source),
+            cli.destination.clone().unwrap(),
+            Direction::Download,
+        ),
+        (None, None) => session::locations(source, cli.destination.clone().unwrap())?,
+    };
+    // without a mount only scp is left
+    let scp = match cli.backend {
+        Backend::Scp => true,
+        Backend::Auto => match (detect::unmountable(), scp::mount_only(&cli)) {
+            // rather than silently dropping what was asked for
+            (Some(reason), Some(flag)) => bail!("{reason}, and {flag} needs an sshfs mount"),
+            (Some(reason), None) => {
+                warn!("{reason}, falling back to `scp`");
+                true
             }
-        }
+            (None, _) => {
+                info!("sshfs and FUSE found, copying through the mount");
+                false
+            }
+        },
+        _ => false,
+    };
+    if scp {
+        return scp::run(
+            &cli,
+            &config,
+            &remote_host,
+            remote_path.as_deref(),
+            &local_path,
+            direction,
+        );
     }
-
-    Command::new("umount").arg(mount.path()).status()?;
-    pb.end();
-    Ok(())
+    let session = match sync::connect(&cli, &remote_host, || {
+        Session::connect(
+            remote_host.clone(),
+            remote_path.as_deref(),
+            local_path.clone(),
+            direction,
+            &open_opts,
+            &config,
+        )
+    }) {
+        Err(e) if e.is::<mount::NoSftp>() && cli.backend == Backend::Auto => {
+            if let Some(flag) = scp::mount_only(&cli) {
+                bail!("{e}, and {flag} needs an sshfs mount");
+            }
+            warn!("{e}, falling back to `scp`");
+            return scp::run(
+                &cli,
+                &config,
+                &remote_host,
+                remote_path.as_deref(),
+                &local_path,
+                direction,
+            );
+        }
+        session => session?,
+    };
+    output::field("engine", "sftp");
+    sync::run(&cli, &config, &session)
 }
 
 fn get_remote_host(remote_host: &str) -> Result<HostParams> {
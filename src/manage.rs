@@ -0,0 +1,334 @@
+use crate::checksum;
+use crate::get_remote_host;
+use crate::mount::Mount;
+use crate::output;
+use crate::session::split_remote;
+use crate::state::State;
+use crate::transfer::join;
+use crate::transfer::mkdirs;
+use crate::walk;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use ignore::WalkBuilder;
+use indicatif::HumanBytes;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use time::macros::format_description;
+use time::OffsetDateTime;
+use time::UtcOffset;
+
+/// A `remote-host:path` argument of the management subcommands, with the
+/// remote `/` mounted
+pub struct Location {
+    pub remote_host: String,
+    /// the absolute remote path
+    pub remote: PathBuf,
+    /// the same path under the mount
+    pub mounted: PathBuf,
+    mount: Mount,
+}
+
+impl Location {
+    /// `~` and relative paths start from the remote home, like scp
+    pub fn open(location: &str) -> Result<Location> {
+        let (remote_host, remote) = parse(location)?;
+        let mount = Mount::new(remote_host)?;
+        let mounted = mount.mounted(&remote);
+        Ok(Location {
+            remote_host: remote_host.to_owned(),
+            remote,
+            mounted,
+            mount,
+        })
+    }
+
+    /// Where another absolute path of the same host is found under the mount
+    pub fn mounted(&self, remote: &Path) -> PathBuf {
+        self.mount.mounted(remote)
+    }
+}
+
+/// The host and absolute remote path of a `remote-host:path` argument
+fn parse(location: &str) -> Result<(&str, PathBuf)> {
+    let Some((remote_host, path)) = split_remote(location) else {
+        bail!("Expect `remote-host:path`, got {location:?}");
+    };
+    let remote_home = get_remote_host(remote_host)?
+        .user
+        .map(|u| format!("/home/{u}"));
+    let path = shellexpand::tilde_with_context(path, || remote_home.clone());
+    let remote = if Path::new(path.as_ref()).is_absolute() {
+        PathBuf::from(path.as_ref())
+    } else {
+        match &remote_home {
+            Some(home) => Path::new(home).join(path.as_ref()),
+            None => bail!("Unknown remote home, give an absolute path"),
+        }
+    };
+    Ok((remote_host, remote))
+}
+
+/// List a remote directory (or a single file) in a long format, optionally
+/// marking the git-ignored entries
+pub fn ls(location: &str, ignored: bool) -> Result<()> {
+    let location = Location::open(location)?;
+    let path = &location.mounted;
+    let metadata = path
+        .symlink_metadata()
+        .with_context(|| format!("Fail to stat {:?}", location.remote))?;
+    if !metadata.is_dir() {
+        long_line(path, &metadata, false)?;
+        return Ok(());
+    }
+
+    let mut entries = std::fs::read_dir(path)
+        .with_context(|| format!("Fail to list {:?}", location.remote))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    // what a walk honoring .gitignore still sees
+    let visible: HashSet<PathBuf> = if ignored {
+        WalkBuilder::new(path)
+            .max_depth(Some(1))
+            .hidden(false)
+            .git_ignore(true)
+            .require_git(false)
+            .build()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.into_path())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    for entry in entries {
+        let path = entry.path();
+        long_line(
+            &path,
+            &entry.metadata()?,
+            ignored && !visible.contains(&path),
+        )?;
+    }
+    Ok(())
+}
+
+/// Delete a remote file, or a whole directory with `recursive`, after asking
+/// unless `force`
+pub fn rm(location: &str, recursive: bool, force: bool) -> Result<()> {
+    let location = Location::open(location)?;
+    let remote = &location.remote;
+    let metadata = location
+        .mounted
+        .symlink_metadata()
+        .with_context(|| format!("Fail to stat {remote:?}"))?;
+    if metadata.is_dir() && !recursive {
+        bail!("{remote:?} is a directory, use -r to remove it");
+    }
+    if !force && !confirm(&format!("remove {remote:?}?"))? {
+        return Ok(());
+    }
+    let removed = if metadata.is_dir() {
+        std::fs::remove_dir_all(&location.mounted)
+    } else {
+        std::fs::remove_file(&location.mounted)
+    };
+    removed.with_context(|| format!("Fail to remove {remote:?}"))?;
+    output::event("deleted", &[remote]);
+    Ok(())
+}
+
+/// Create a remote directory, with `parents` also its missing ancestors and
+/// no error if it exists
+pub fn mkdir(location: &str, parents: bool) -> Result<()> {
+    let location = Location::open(location)?;
+    let remote = &location.remote;
+    if parents {
+        mkdirs(&location.mounted)?;
+    } else {
+        std::fs::create_dir(&location.mounted)
+            .with_context(|| format!("Fail to create directory {remote:?}"))?;
+    }
+    output::event("created", &[remote]);
+    Ok(())
+}
+
+/// Rename a remote file or directory on the same host, into `to` when it is an
+/// existing directory
+pub fn mv(from: &str, to: &str) -> Result<()> {
+    let from = Location::open(from)?;
+    let (to_host, mut to) = parse(to)?;
+    if from.remote_host != to_host {
+        bail!("Can only rename within the same remote host");
+    }
+    let mut mounted_to = from.mounted(&to);
+    if mounted_to.is_dir() {
+        let name = from
+            .remote
+            .file_name()
+            .with_context(|| format!("Fail to find the file name of {:?}", from.remote))?;
+        to.push(name);
+        mounted_to.push(name);
+    }
+    std::fs::rename(&from.mounted, &mounted_to)
+        .with_context(|| format!("Fail to rename {:?} to {to:?}", from.remote))?;
+    output::event("renamed", &[&from.remote, &to]);
+    Ok(())
+}
+
+/// Print the total size of files under each remote directory, down to
+/// `max_depth` levels, skipping git-ignored content with `gitignore`
+pub fn du(location: &str, max_depth: Option<usize>, gitignore: bool) -> Result<()> {
+    let location = Location::open(location)?;
+    let root = &location.mounted;
+    let mut sizes: BTreeMap<PathBuf, u64> = BTreeMap::new();
+    for entry in WalkBuilder::new(root)
+        .standard_filters(false)
+        .git_ignore(gitignore)
+        .require_git(false)
+        .build()
+    {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let rel = entry.path().strip_prefix(root)?;
+        if metadata.is_dir() {
+            sizes.entry(rel.to_path_buf()).or_default();
+            continue;
+        }
+        // every directory above the file counts it, a lone file counts itself
+        let skip = if rel.as_os_str().is_empty() { 0 } else { 1 };
+        for dir in rel.ancestors().skip(skip) {
+            *sizes.entry(dir.to_path_buf()).or_default() += metadata.len();
+        }
+    }
+    for (dir, size) in &sizes {
+        if max_depth.is_none_or(|max| dir.components().count() <= max) {
+            let path = join(&location.remote, dir);
+            let human = format!("{:>10}  {}", HumanBytes(*size).to_string(), path.display());
+            output::row(&human, &[OsStr::new(&size.to_string()), path.as_os_str()]);
+        }
+    }
+    Ok(())
+}
+
+/// Print a `sha256sum` style manifest of a remote tree, hashed remotely, or
+/// with `compare` list how a local tree differs from it
+pub fn checksum(location: &str, compare: Option<&str>) -> Result<()> {
+    let location = Location::open(location)?;
+    let remote_files = walk::files(&location.mounted)?;
+    let remote_hashes = checksum::remote(&location.remote_host, &location.remote, &remote_files)?;
+    let Some(local_root) = compare else {
+        let manifest: BTreeMap<_, _> = remote_hashes.iter().collect();
+        for (file, hash) in manifest {
+            let path = join(&location.remote, file);
+            output::row(
+                &format!("{hash}  {}", path.display()),
+                &[OsStr::new(hash), path.as_os_str()],
+            );
+        }
+        return Ok(());
+    };
+
+    let local_root = Path::new(local_root);
+    let local_files = walk::files(local_root)?;
+    let local_hashes = checksum::local(local_root, &local_files, &State::default())?;
+    let all: BTreeSet<&PathBuf> = remote_hashes.keys().chain(local_hashes.keys()).collect();
+    let mut differences = 0;
+    for file in all {
+        let path = join(local_root, file);
+        match (remote_hashes.get(file), local_hashes.get(file)) {
+            (Some(remote), Some(local)) if remote == local => continue,
+            (Some(_), Some(_)) => output::event("differs", &[&path]),
+            (Some(_), None) => output::event("only remote", &[&join(&location.remote, file)]),
+            (None, _) => output::event("only local", &[&path]),
+        }
+        differences += 1;
+    }
+    if differences > 0 {
+        bail!("{differences} files differ");
+    }
+    output::field("matching", &remote_hashes.len().to_string());
+    Ok(())
+}
+
+/// Ask a yes/no `question` on the terminal, no by default
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{question} [y/N] ");
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(matches!(line.trim(), "y" | "yes"))
+}
+
+/// Print the `ls -l` style line of `path`: type and permissions, size, mtime
+/// and name, marked when `ignored`
+fn long_line(path: &Path, metadata: &Metadata, ignored: bool) -> Result<()> {
+    let name = path.file_name().unwrap_or_default();
+    let (mode, size, mtime) = (mode(metadata), metadata.len().to_string(), mtime(metadata)?);
+    let mut line = format!("{mode} {size:>12} {mtime} {}", name.to_string_lossy());
+    let target = if metadata.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        line.push_str(&format!(" -> {}", target.display()));
+        target
+    } else {
+        if metadata.is_dir() {
+            line.push('/');
+        }
+        PathBuf::new()
+    };
+    if ignored {
+        line.push_str(" (git-ignored)");
+    }
+    // with --porcelain: mode, size, mtime, name, symlink target, `git-ignored`
+    output::row(
+        &line,
+        &[
+            OsStr::new(&mode),
+            OsStr::new(&size),
+            OsStr::new(&mtime),
+            name,
+            target.as_os_str(),
+            OsStr::new(if ignored { "git-ignored" } else { "" }),
+        ],
+    );
+    Ok(())
+}
+
+/// Like `drwxr-xr-x`
+fn mode(metadata: &Metadata) -> String {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else {
+        '-'
+    };
+    let bits = metadata.permissions().mode();
+    let mut mode = String::from(kind);
+    for (i, c) in "rwxrwxrwx".chars().enumerate() {
+        mode.push(if bits & (0o400 >> i) != 0 { c } else { '-' });
+    }
+    mode
+}
+
+/// The mtime in local time, like `2024-01-31 17:05`
+fn mtime(metadata: &Metadata) -> Result<String> {
+    let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+    let mtime = OffsetDateTime::from(metadata.modified()?).to_offset(offset);
+    Ok(mtime.format(format_description!("[year]-[month]-[day] [hour]:[minute]"))?)
+}
@@ -0,0 +1,101 @@
+use crate::checksum;
+use crate::manage::Location;
+use crate::output;
+use crate::session::split_remote;
+use crate::state::State;
+use crate::transfer::join;
+use crate::walk;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The hashes of a tree at some point, to check it against later
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// the `remote-host:path` or local path it was taken of
+    root: String,
+    files: BTreeMap<PathBuf, Entry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    sha256: String,
+}
+
+/// Write the sizes and SHA-256 hashes of the files of `location`, a
+/// `remote-host:path` or a local path, to the json `manifest`
+pub fn create(location: &str, manifest: &Path) -> Result<()> {
+    let (root, files) = snapshot(location)?;
+    let count = files.len();
+    let manifest_json = serde_json::to_vec_pretty(&Manifest { root, files })?;
+    std::fs::write(manifest, manifest_json)
+        .with_context(|| format!("Fail to write {manifest:?}"))?;
+    output::field("recorded", &count.to_string());
+    output::event("written", &[manifest]);
+    Ok(())
+}
+
+/// Check `location`, by default the one the manifest was taken of, against
+/// `manifest`, failing when anything changed since
+pub fn verify(manifest: &Path, location: Option<&str>) -> Result<()> {
+    let bytes = std::fs::read(manifest).with_context(|| format!("Fail to read {manifest:?}"))?;
+    let manifest: Manifest =
+        serde_json::from_slice(&bytes).with_context(|| format!("Fail to parse {manifest:?}"))?;
+    let location = location.unwrap_or(&manifest.root);
+    let (_, files) = snapshot(location)?;
+    let all: BTreeSet<&PathBuf> = manifest.files.keys().chain(files.keys()).collect();
+    let mut differences = 0;
+    for file in all {
+        match (manifest.files.get(file), files.get(file)) {
+            (Some(recorded), Some(found)) if recorded == found => continue,
+            (Some(_), Some(_)) => output::event("changed", &[file]),
+            (Some(_), None) => output::event("missing", &[file]),
+            (None, _) => output::event("added", &[file]),
+        }
+        differences += 1;
+    }
+    if differences > 0 {
+        bail!("{differences} files differ from the manifest");
+    }
+    output::field("matching", &files.len().to_string());
+    Ok(())
+}
+
+/// The absolute root of `location` and its regular files with their size and
+/// hash, hashed remotely for a remote tree; ignore rules don't apply, every
+/// file counts
+fn snapshot(location: &str) -> Result<(String, BTreeMap<PathBuf, Entry>)> {
+    if split_remote(location).is_some() {
+        let location = Location::open(location)?;
+        let files = walk::all(&location.mounted)?;
+        let hashes = checksum::remote(&location.remote_host, &location.remote, &files)?;
+        let root = format!("{}:{}", location.remote_host, location.remote.display());
+        Ok((root, entries(&location.mounted, hashes)?))
+    } else {
+        let root = Path::new(location)
+            .canonicalize()
+            .with_context(|| format!("Fail to resolve {location:?}"))?;
+        let files = walk::all(&root)?;
+        let hashes = checksum::local(&root, &files, &State::default())?;
+        let entries = entries(&root, hashes)?;
+        Ok((root.to_string_lossy().into_owned(), entries))
+    }
+}
+
+/// `hashes` of files under `root` along with their sizes
+fn entries(root: &Path, hashes: HashMap<PathBuf, String>) -> Result<BTreeMap<PathBuf, Entry>> {
+    let mut entries = BTreeMap::new();
+    for (file, sha256) in hashes {
+        let size = join(root, &file).symlink_metadata()?.len();
+        entries.insert(file, Entry { size, sha256 });
+    }
+    Ok(entries)
+}
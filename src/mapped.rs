@@ -0,0 +1,49 @@
+use crate::transfer;
+use crate::transfer::CopyOpts;
+use anyhow::Context;
+use anyhow::Result;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use xcp::operations::StatSender;
+use xcp::operations::StatusUpdate;
+
+/// Files from this size on are read through a memory map
+pub const THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Bytes handed to one write
+const SLICE: usize = 4 * 1024 * 1024;
+
+/// A read-only map of the local file `path`, None when it is smaller than
+/// `THRESHOLD` or can't be mapped
+pub fn map(path: &Path) -> Option<Mmap> {
+    let file = File::open(path).ok()?;
+    if file.metadata().ok()?.len() < THRESHOLD {
+        return None;
+    }
+    // the source is not expected to change under our feet, like with a read
+    let map = unsafe { Mmap::map(&file) }.ok()?;
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Some(map)
+}
+
+/// Copy a large local `from` to `to` by writing slices of its map directly,
+/// without a copy through a buffer of ours, reporting each to `stats`
+///
+/// Returns false, touching nothing, when `from` isn't mapped; it is then to be
+/// copied with regular reads
+pub fn copy(from: &Path, to: &Path, stats: &StatSender, copy_opts: &CopyOpts) -> Result<bool> {
+    let Some(map) = map(from) else {
+        return Ok(false);
+    };
+    let mut writer = File::create(to).with_context(|| format!("Fail to create {to:?}"))?;
+    for slice in map.chunks(SLICE) {
+        writer
+            .write_all(slice)
+            .with_context(|| format!("Fail to write {to:?}"))?;
+        stats.send(StatusUpdate::Copied(slice.len() as u64))?;
+    }
+    transfer::finish_copy(&writer, &std::fs::metadata(from)?, to, copy_opts)?;
+    Ok(true)
+}
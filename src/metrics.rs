@@ -0,0 +1,106 @@
+use crate::report::Report;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// The metrics of a run to `remote_host` in the Prometheus text format, gauges
+/// of the last run so a monitoring rule can alert on failing or stale syncs
+pub fn text(remote_host: &str, report: &Report, duration: Duration, success: bool) -> String {
+    let host = label(remote_host);
+    let mut text = String::new();
+    let mut gauge = |name: &str, help: &str, values: &[(String, f64)]| {
+        text.push_str(&format!("# HELP smartscp_{name} {help}\n"));
+        text.push_str(&format!("# TYPE smartscp_{name} gauge\n"));
+        for (labels, value) in values {
+            text.push_str(&format!(
+                "smartscp_{name}{{remote_host=\"{host}\"{labels}}} {value}\n"
+            ));
+        }
+    };
+    let totals = report.totals();
+    let status = |status: &str| totals.get(status).copied().unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    gauge(
+        "last_run_success",
+        "Whether the last run succeeded",
+        &[(String::new(), if success { 1.0 } else { 0.0 })],
+    );
+    gauge(
+        "last_run_timestamp_seconds",
+        "When the last run ended",
+        &[(String::new(), now.as_secs() as f64)],
+    );
+    gauge(
+        "last_run_duration_seconds",
+        "How long the last run took",
+        &[(String::new(), duration.as_secs_f64())],
+    );
+    gauge(
+        "last_run_bytes_transferred",
+        "Bytes copied by the last run",
+        &[(String::new(), status("copied").1 as f64)],
+    );
+    gauge(
+        "last_run_files",
+        "Files of the last run per outcome",
+        &totals
+            .iter()
+            .map(|(status, (files, _))| (format!(",status=\"{status}\""), *files as f64))
+            .collect::<Vec<_>>(),
+    );
+    gauge(
+        "last_run_failures",
+        "Files the last run failed to copy",
+        &[(String::new(), status("failed").0 as f64)],
+    );
+    text.push_str("# EOF\n");
+    text
+}
+
+/// Write `text` to the file `path` for the node exporter textfile collector,
+/// atomically so it never reads half a file
+pub fn write(path: &Path, text: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, text).with_context(|| format!("Fail to write {tmp:?}"))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Fail to write {path:?}"))
+}
+
+/// Push `text` to the Prometheus pushgateway at `url` under `job`, through
+/// `curl`
+///
+/// `remote_host` is part of the grouping key, so the pushes of runs to other
+/// hosts don't replace it
+pub fn push(url: &str, job: &str, remote_host: &str, text: &str) -> Result<()> {
+    let url = format!(
+        "{}/metrics/job/{job}/remote_host/{remote_host}",
+        url.trim_end_matches('/')
+    );
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--data-binary", "@-"])
+        .arg(&url)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Fail to execute `curl`, maybe `curl` not found ?")?;
+    child.stdin.take().unwrap().write_all(text.as_bytes())?;
+    if !child.wait()?.success() {
+        bail!("Fail to push metrics to {url}");
+    }
+    Ok(())
+}
+
+/// `value` escaped for a label value
+fn label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
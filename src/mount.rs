@@ -0,0 +1,160 @@
+use crate::daemon;
+use crate::detect;
+use crate::remote;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use pathdiff::diff_paths;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+/// `-o` options of every later `sshfs` run
+static SSHFS_OPTIONS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Pass `-o option` to every later `sshfs` run
+pub fn add_sshfs_option(option: String) {
+    SSHFS_OPTIONS.lock().unwrap().push(option);
+}
+
+/// `remote_host` offers no sftp subsystem to mount
+#[derive(Debug)]
+pub struct NoSftp(pub String);
+
+impl fmt::Display for NoSftp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} offers no sftp subsystem", self.0)
+    }
+}
+
+impl std::error::Error for NoSftp {}
+
+/// An sshfs mount of the remote `/`, in a temporary directory unmounted on
+/// drop, or kept by `smartscp daemon`
+pub struct Mount {
+    dir: PathBuf,
+    /// `None` for a mount of the daemon, left mounted
+    temp: Option<TempDir>,
+}
+
+impl Mount {
+    /// The mount of a running `smartscp daemon`, whose master connection the
+    /// `ssh` runs go through too, or else a new one; the daemon mounted with
+    /// no options, so sshfs options like `max_conns` need a new one too
+    pub fn new(remote_host: &str) -> Result<Mount> {
+        let daemon_dir = if SSHFS_OPTIONS.lock().unwrap().is_empty() {
+            daemon::lookup(remote_host)
+        } else {
+            None
+        };
+        if let Some(dir) = daemon_dir {
+            let mut control_path = OsString::from("ControlPath=");
+            control_path.push(remote::daemon_control_path());
+            remote::add_ssh_option(
+                Some(remote_host),
+                control_path.to_string_lossy().into_owned(),
+            );
+            return Ok(Mount { dir, temp: None });
+        }
+        Mount::own(remote_host)
+    }
+
+    /// A new mount of our own
+    ///
+    /// sshfs keeps the attributes of directory listings for its default 20s,
+    /// long enough for the walk's stats to be no round trips of their own, and
+    /// short enough not to go stale under remote commands or the daemon
+    pub fn own(remote_host: &str) -> Result<Mount> {
+        if let Some(reason) = detect::unmountable() {
+            bail!("Fail to mount {remote_host}:/, {reason}");
+        }
+        let dir = tempfile::tempdir()?;
+        let mut stderr = tempfile::tempfile()?;
+        let status = Command::new("sshfs")
+            .args(remote::ssh_options(remote_host))
+            // a dropped connection is opened again by the next operation
+            .args(["-o", "reconnect"])
+            .args(
+                SSHFS_OPTIONS
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .flat_map(|option| ["-o", option.as_str()]),
+            )
+            .arg(format!("{remote_host}:/"))
+            .arg(dir.path())
+            // not a pipe, which the sshfs daemon would keep open
+            .stderr(stderr.try_clone()?)
+            .status()
+            .context("Fail to execute `sshfs`, maybe `sshfs` not found ?")?;
+        if !status.success() {
+            let mut message = String::new();
+            stderr.seek(SeekFrom::Start(0))?;
+            stderr.read_to_string(&mut message)?;
+            eprint!("{message}");
+            if message.contains("subsystem request failed") {
+                return Err(NoSftp(remote_host.to_owned()).into());
+            }
+            bail!("Fail to mount {remote_host}:/ with `sshfs`");
+        }
+        Ok(Mount {
+            dir: dir.path().to_path_buf(),
+            temp: Some(dir),
+        })
+    }
+
+    /// Whether it is still mounted, not detached by a timeout
+    pub fn alive(&self) -> bool {
+        let dev = |path: &Path| path.metadata().map(|m| m.dev()).ok();
+        // a mount point is on another device than its parent
+        match (dev(&self.dir), self.dir.parent().and_then(dev)) {
+            (Some(dir), Some(parent)) => dir != parent,
+            _ => false,
+        }
+    }
+
+    /// Whether the remote is Windows, whose OpenSSH serves the drives as `/C:`
+    /// and so on
+    pub fn is_windows(&self) -> bool {
+        self.dir.join("C:").is_dir()
+    }
+
+    /// Where the remote absolute `path` is found under the mount
+    pub fn mounted(&self, path: &Path) -> PathBuf {
+        self.dir.join(diff_paths(path, "/").unwrap())
+    }
+
+    /// The remote path of `mounted`, a path under the mount
+    pub fn remote_path(&self, mounted: &Path) -> Result<PathBuf> {
+        Ok(Path::new("/").join(mounted.strip_prefix(&self.dir)?))
+    }
+}
+
+/// Detach the mount at `dir` even while operations on it hang, making them
+/// fail
+pub fn force_unmount(dir: &Path) {
+    let detached = Command::new("fusermount")
+        .args(["-u", "-z"])
+        .arg(dir)
+        .status()
+        .is_ok_and(|status| status.success());
+    if !detached {
+        let _ = Command::new("umount").arg("-l").arg(dir).status();
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if self.temp.is_some() {
+            let _ = Command::new("umount").arg(&self.dir).status();
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::ffi::OsStr;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// Print the stable format of `--porcelain` instead of the one for humans
+static PORCELAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_porcelain(porcelain: bool) {
+    PORCELAIN.store(porcelain, Ordering::Relaxed);
+}
+
+pub fn porcelain() -> bool {
+    PORCELAIN.load(Ordering::Relaxed)
+}
+
+/// Report that `kind` happened to `paths`, like `deleted: "a"` or
+/// `trashed: "a" -> "b"`
+///
+/// With --porcelain it is the `kind` and the raw paths separated by tabs, with
+/// `\`, tabs and newlines of the paths escaped
+pub fn event(kind: &str, paths: &[&Path]) {
+    if porcelain() {
+        let mut fields = vec![OsStr::new(kind)];
+        fields.extend(paths.iter().map(|path| path.as_os_str()));
+        line(&fields);
+    } else {
+        let paths: Vec<String> = paths.iter().map(|path| format!("{path:?}")).collect();
+        println!("{kind}: {}", paths.join(" -> "));
+    }
+}
+
+/// Report `value` of `key`, like `engine: sftp`
+pub fn field(key: &str, value: &str) {
+    if porcelain() {
+        line(&[OsStr::new(key), OsStr::new(value)]);
+    } else {
+        println!("{key}: {value}");
+    }
+}
+
+/// Print a line of a listing, `human` or with --porcelain its raw `fields`
+pub fn row(human: &str, fields: &[&OsStr]) {
+    if porcelain() {
+        line(fields);
+    } else {
+        println!("{human}");
+    }
+}
+
+/// Print `fields` separated by tabs
+fn line(fields: &[&OsStr]) {
+    let mut line = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        for &b in field.as_bytes() {
+            match b {
+                b'\\' => line.extend_from_slice(br"\\"),
+                b'\t' => line.extend_from_slice(br"\t"),
+                b'\n' => line.extend_from_slice(br"\n"),
+                b'\r' => line.extend_from_slice(br"\r"),
+                _ => line.push(b),
+            }
+        }
+    }
+    line.push(b'\n');
+    let _ = std::io::stdout().lock().write_all(&line);
+}
@@ -0,0 +1,261 @@
+use crate::git;
+use crate::remote;
+use crate::state;
+use crate::transfer;
+use crate::transfer::CopyOpts;
+use crate::trash;
+use anyhow::bail;
+use anyhow::Result;
+use log::info;
+use log::warn;
+use sha2::Digest;
+use sha2::Sha256;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use xcp::operations::StatSender;
+use xcp::options::Opts;
+
+/// A repo to patch: the local working tree, the remote repo and the same
+/// remote repo seen through the mount
+struct Repo {
+    source: PathBuf,
+    remote_dir: PathBuf,
+    dest: PathBuf,
+}
+
+/// Sync `source` to the remote repo at `remote_dir` by applying `git diff HEAD`
+/// remotely, then copying added and untracked files to `dest` (the same repo
+/// seen through the mount)
+///
+/// With `deletions`, files deleted locally are moved into `trash` if any, ahead
+/// of a patch which then leaves them out. Submodules are patched the same way, recursively. Without `deletions`,
+/// files deleted locally are kept on the remote, and so are uncommitted remote
+/// changes: patch mode is then skipped for a remote working tree which has any
+/// but those the last patch left, see `state::patched`
+///
+/// Returns false when the remote repo is missing, on another commit, or the
+/// patch doesn't apply; the caller should fall back to a plain file transfer.
+/// The HEAD of every submodule is checked before anything is touched, and
+/// every patch before any is applied
+#[allow(clippy::too_many_arguments)]
+pub fn upload(
+    remote_host: &str,
+    remote_dir: &Path,
+    opts: &Arc<Opts>,
+    source: &Path,
+    dest: &Path,
+    stats: StatSender,
+    deletions: bool,
+    trash: Option<&Path>,
+) -> Result<bool> {
+    let mut repos = vec![Repo {
+        source: source.to_path_buf(),
+        remote_dir: remote_dir.to_path_buf(),
+        dest: dest.to_path_buf(),
+    }];
+    // parents before their submodules
+    let mut i = 0;
+    while i < repos.len() {
+        for submodule in git::submodules(&repos[i].source)? {
+            let parent = &repos[i];
+            let repo = Repo {
+                source: parent.source.join(&submodule),
+                remote_dir: parent.remote_dir.join(&submodule),
+                dest: parent.dest.join(&submodule),
+            };
+            repos.push(repo);
+        }
+        i += 1;
+    }
+
+    for repo in &repos {
+        let local_head = git::head(&repo.source)?;
+        let out = remote::run(
+            remote_host,
+            &remote::git(&repo.remote_dir, &["rev-parse", "HEAD"]),
+            None,
+        )?;
+        let remote_head = String::from_utf8_lossy(&out.stdout).trim().to_owned();
+        if !out.status.success() || remote_head != local_head {
+            info!(
+                "remote HEAD {remote_head:?} of {:?} differs from local {local_head:?}, skip patch mode",
+                repo.remote_dir
+            );
+            return Ok(false);
+        }
+    }
+
+    let mut lfs = Vec::new();
+    for repo in &repos {
+        if !reset(remote_host, repo, deletions, &mut lfs)? {
+            return Ok(false);
+        }
+    }
+
+    let mut patches = Vec::new();
+    for repo in &repos {
+        if !deletions {
+            for file in git::deleted_files(&repo.source)? {
+                info!("skip deleting {:?}", repo.dest.join(file));
+            }
+        }
+        let patch = git::diff_head(&repo.source, deletions && trash.is_none())?;
+        if !patch.is_empty() {
+            let out = remote::run(
+                remote_host,
+                &remote::git(
+                    &repo.remote_dir,
+                    &["apply", "--check", "--whitespace=nowarn"],
+                ),
+                Some(&patch),
+            )?;
+            if !out.status.success() {
+                info!(
+                    "patch doesn't apply to {:?}, skip patch mode: {}",
+                    repo.remote_dir,
+                    remote::stderr(&out)
+                );
+                return Ok(false);
+            }
+        }
+        patches.push(patch);
+    }
+
+    for ((repo, patch), lfs) in repos.iter().zip(&patches).zip(lfs) {
+        if let (true, Some(trash)) = (deletions, trash) {
+            let rel = repo.source.strip_prefix(source).unwrap();
+            for file in git::deleted_files(&repo.source)? {
+                trash::move_to(&repo.dest.join(&file), trash, &rel.join(&file))?;
+            }
+        }
+        if !patch.is_empty() {
+            let out = remote::run(
+                remote_host,
+                &remote::git(&repo.remote_dir, &["apply", "--whitespace=nowarn"]),
+                Some(patch),
+            )?;
+            if !out.status.success() {
+                bail!(
+                    "Fail to apply the patch to {:?} after checking it: {}",
+                    repo.remote_dir,
+                    remote::stderr(&out)
+                );
+            }
+        }
+
+        let mut files = git::added_files(&repo.source)?;
+        files.extend(git::untracked_files(&repo.source)?);
+        files.extend(lfs);
+        info!(
+            "patch applied to {:?}, copying {} new files",
+            repo.remote_dir,
+            files.len()
+        );
+        transfer::copy_files(
+            opts,
+            &repo.source,
+            &files,
+            &repo.dest,
+            stats.clone(),
+            &CopyOpts::default(),
+        )?;
+        let diff = remote_diff(remote_host, repo)?;
+        state::set_patched(remote_host, &repo.remote_dir, &diff)?;
+    }
+    Ok(true)
+}
+
+/// The hash of the remote `git diff HEAD` of `repo`, what `reset` compares
+/// with the one the last patch left
+fn remote_diff(remote_host: &str, repo: &Repo) -> Result<String> {
+    let out = remote::run(
+        remote_host,
+        &remote::git(
+            &repo.remote_dir,
+            &[
+                "diff",
+                "HEAD",
+                "--binary",
+                "--no-color",
+                "--ignore-submodules=all",
+                "--",
+                ".",
+            ],
+        ),
+        None,
+    )?;
+    if !out.status.success() {
+        bail!(
+            "Fail to diff the remote repo {:?}: {}",
+            repo.remote_dir,
+            remote::stderr(&out)
+        );
+    }
+    Ok(format!("{:x}", Sha256::digest(&out.stdout)))
+}
+
+/// Leave the remote working tree of `repo` clean at HEAD, discarding its
+/// changes only with `discard` or when the last patch left them, and add its
+/// LFS files to `lfs`; false when it can't be
+fn reset(
+    remote_host: &str,
+    repo: &Repo,
+    discard: bool,
+    lfs: &mut Vec<Vec<PathBuf>>,
+) -> Result<bool> {
+    let files = git::lfs_files(&repo.source)?;
+    // no one else's changes to keep
+    let discard = discard
+        || state::patched(remote_host, &repo.remote_dir)?
+            .is_some_and(|hash| remote_diff(remote_host, repo).is_ok_and(|diff| diff == hash));
+    if discard {
+        // LFS content is uploaded from here rather than fetched by the remote:
+        // drop it so the checkout below restores plain pointer files
+        for file in &files {
+            match std::fs::remove_file(repo.dest.join(file)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        let out = remote::run(
+            remote_host,
+            &remote::git(&repo.remote_dir, &["checkout", "--", "."]),
+            None,
+        )?;
+        if !out.status.success() {
+            info!(
+                "fail to reset remote working tree, skip patch mode: {}",
+                remote::stderr(&out)
+            );
+            return Ok(false);
+        }
+    }
+    lfs.push(files);
+    // a clean tree to patch, which without `discard` the remote must have been
+    let out = remote::run(
+        remote_host,
+        &remote::git(
+            &repo.remote_dir,
+            &[
+                "status",
+                "--porcelain",
+                "--untracked-files=no",
+                "--ignore-submodules=all",
+                "--",
+                ".",
+            ],
+        ),
+        None,
+    )?;
+    if !out.status.success() || !out.stdout.is_empty() {
+        warn!(
+            "remote working tree has changes not of the last patch, skip patch mode (--delete discards them): {}{}",
+            String::from_utf8_lossy(&out.stdout).trim(),
+            remote::stderr(&out)
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
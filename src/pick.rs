@@ -0,0 +1,185 @@
+use anyhow::Result;
+use crossterm::event;
+use crossterm::event::Event;
+use crossterm::event::KeyCode;
+use crossterm::event::KeyEventKind;
+use crossterm::execute;
+use crossterm::terminal::disable_raw_mode;
+use crossterm::terminal::enable_raw_mode;
+use crossterm::terminal::EnterAlternateScreen;
+use crossterm::terminal::LeaveAlternateScreen;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::Constraint;
+use ratatui::layout::Direction as LayoutDirection;
+use ratatui::layout::Layout;
+use ratatui::style::Modifier;
+use ratatui::style::Style;
+use ratatui::widgets::Block;
+use ratatui::widgets::Borders;
+use ratatui::widgets::List;
+use ratatui::widgets::ListItem;
+use ratatui::widgets::ListState;
+use ratatui::Terminal;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+const HELP: &str = "space mark, enter open, backspace up, tab switch, c copy, q quit";
+
+/// One side of the browser, listing a directory below `root`
+struct Pane {
+    root: PathBuf,
+    /// relative to `root`
+    dir: PathBuf,
+    /// names, and whether they are directories
+    entries: Vec<(String, bool)>,
+    state: ListState,
+}
+
+impl Pane {
+    fn new(root: &Path) -> Result<Pane> {
+        let mut pane = Pane {
+            root: root.to_path_buf(),
+            dir: PathBuf::new(),
+            entries: Vec::new(),
+            state: ListState::default(),
+        };
+        pane.load()?;
+        Ok(pane)
+    }
+
+    /// Read the current directory, directories first; a missing one is empty
+    fn load(&mut self) -> Result<()> {
+        self.entries.clear();
+        if let Ok(read_dir) = std::fs::read_dir(self.root.join(&self.dir)) {
+            for entry in read_dir {
+                let entry = entry?;
+                let is_dir = entry.file_type()?.is_dir();
+                self.entries
+                    .push((entry.file_name().to_string_lossy().into_owned(), is_dir));
+            }
+        }
+        self.entries
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        self.state.select((!self.entries.is_empty()).then_some(0));
+        Ok(())
+    }
+
+    fn selected(&self) -> Option<&(String, bool)> {
+        self.state.selected().and_then(|i| self.entries.get(i))
+    }
+
+    fn step(&mut self, down: bool) {
+        if let Some(i) = self.state.selected() {
+            let i = if down {
+                (i + 1).min(self.entries.len() - 1)
+            } else {
+                i.saturating_sub(1)
+            };
+            self.state.select(Some(i));
+        }
+    }
+
+    fn open(&mut self) -> Result<()> {
+        if let Some((name, true)) = self.selected() {
+            self.dir = self.dir.join(name);
+            self.load()?;
+        }
+        Ok(())
+    }
+
+    fn up(&mut self) -> Result<()> {
+        if self.dir.pop() {
+            self.load()?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, title: String, marked: &BTreeSet<PathBuf>, focused: bool) -> List<'static> {
+        let items: Vec<ListItem> = self
+            .entries
+            .iter()
+            .map(|(name, is_dir)| {
+                let mark = if marked.contains(&self.dir.join(name)) {
+                    "* "
+                } else {
+                    "  "
+                };
+                let slash = if *is_dir { "/" } else { "" };
+                ListItem::new(format!("{mark}{name}{slash}"))
+            })
+            .collect();
+        let highlight = if focused {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default().add_modifier(Modifier::UNDERLINED)
+        };
+        List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(highlight)
+    }
+}
+
+/// Browse `source` next to `dest` on the terminal and mark what to copy
+///
+/// Returns the marked paths relative to `source`, or None when the user quits
+pub fn run(source: &Path, dest: &Path) -> Result<Option<Vec<PathBuf>>> {
+    enable_raw_mode()?;
+    execute!(std::io::stderr(), EnterAlternateScreen)?;
+    let picked = browse(source, dest);
+    // restore the terminal even when browsing failed
+    disable_raw_mode()?;
+    execute!(std::io::stderr(), LeaveAlternateScreen)?;
+    picked
+}
+
+fn browse(source: &Path, dest: &Path) -> Result<Option<Vec<PathBuf>>> {
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stderr()))?;
+    let mut panes = [Pane::new(source)?, Pane::new(dest)?];
+    let mut focus = 0;
+    let mut marked = BTreeSet::new();
+    loop {
+        terminal.draw(|frame| {
+            let chunks = Layout::default()
+                .direction(LayoutDirection::Horizontal)
+                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .split(frame.size());
+            let titles = [
+                format!("from {} ({HELP})", source.join(&panes[0].dir).display()),
+                format!("to {}", dest.join(&panes[1].dir).display()),
+            ];
+            for (i, title) in titles.into_iter().enumerate() {
+                let list = panes[i].list(title, &marked, focus == i);
+                frame.render_stateful_widget(list, chunks[i], &mut panes[i].state);
+            }
+        })?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let pane = &mut panes[focus];
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+            KeyCode::Char('c') => return Ok(Some(marked.into_iter().collect())),
+            KeyCode::Tab => focus = 1 - focus,
+            KeyCode::Down | KeyCode::Char('j') => pane.step(true),
+            KeyCode::Up | KeyCode::Char('k') => pane.step(false),
+            KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => pane.open()?,
+            KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => pane.up()?,
+            // only the source side is copied
+            KeyCode::Char(' ') if focus == 0 => {
+                if let Some((name, _)) = pane.selected() {
+                    let path = pane.dir.join(name);
+                    if !marked.remove(&path) {
+                        marked.insert(path);
+                    }
+                    pane.step(true);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use indicatif::MultiProgress;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use xcp::operations::StatusUpdate;
+
+/// An overall bar of the bytes copied, with throughput and ETA, above a line
+/// counting the files and naming the last one started
+///
+/// Both are hidden when stderr is no terminal
+#[derive(Clone)]
+pub struct Progress {
+    bytes: ProgressBar,
+    files: ProgressBar,
+    /// the totals are known up front, see `start`
+    sized: Arc<AtomicBool>,
+    /// when each file being copied was started
+    started: Arc<Mutex<HashMap<PathBuf, Instant>>>,
+}
+
+/// Shared by the runs to several hosts at once, so their bars stack instead of
+/// overwriting each other
+static BARS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+impl Progress {
+    pub fn new() -> Progress {
+        let bars = &BARS;
+        let bytes = bars.add(ProgressBar::new(0));
+        bytes.set_style(
+            ProgressStyle::with_template(
+                "{prefix}[{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}",
+            )
+            .unwrap(),
+        );
+        let files = bars.add(ProgressBar::new(0));
+        files.set_style(
+            ProgressStyle::with_template("{prefix}{spinner} {pos}/{len} files {wide_msg}").unwrap(),
+        );
+        Progress {
+            bytes,
+            files,
+            sized: Arc::default(),
+            started: Arc::default(),
+        }
+    }
+
+    /// Name the bars after `remote_host`, among those of other hosts
+    pub fn label(&self, remote_host: &str) {
+        self.bytes.set_prefix(format!("{remote_host}: "));
+        self.files.set_prefix(format!("{remote_host}: "));
+    }
+
+    /// Spin while the source is walked
+    pub fn scanning(&self, source: &Path) {
+        self.files
+            .set_message(format!("scanning {}", source.display()));
+        self.files.enable_steady_tick(Duration::from_millis(100));
+    }
+
+    /// `files` of `bytes` in total are about to be copied
+    pub fn start(&self, files: u64, bytes: u64) {
+        self.sized.store(true, Ordering::Relaxed);
+        self.files.set_length(files);
+        self.files.set_message("");
+        self.files.disable_steady_tick();
+        self.bytes.set_length(bytes);
+    }
+
+    /// Account for an update of xcp's stats channel
+    pub fn update(&self, stat: &StatusUpdate) {
+        match stat {
+            StatusUpdate::Copied(v) => self.bytes.inc(*v),
+            StatusUpdate::Size(v) if !self.sized.load(Ordering::Relaxed) => {
+                self.bytes.inc_length(*v)
+            }
+            StatusUpdate::Size(_) => {}
+            StatusUpdate::Error(_) => {}
+        }
+    }
+
+    /// `path` is being copied
+    pub fn start_file(&self, path: &Path) {
+        self.started
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), Instant::now());
+        self.files.set_message(path.display().to_string());
+    }
+
+    /// `path` is copied, with `bytes` not reported by xcp
+    pub fn finish_file(&self, path: &Path, bytes: u64) {
+        self.started.lock().unwrap().remove(path);
+        if !self.sized.load(Ordering::Relaxed) {
+            self.bytes.inc_length(bytes);
+        }
+        self.bytes.inc(bytes);
+        self.files.inc(1);
+    }
+
+    /// Call `on_expired` once, from another thread and with the reason, when
+    /// no byte moved for `stall` while copying or a file takes longer than
+    /// `file_timeout`
+    pub fn watch<F>(&self, stall: Option<Duration>, file_timeout: Option<Duration>, on_expired: F)
+    where
+        F: FnOnce(String) + Send + 'static,
+    {
+        let progress = self.clone();
+        std::thread::spawn(move || {
+            let mut last = (progress.bytes.position(), progress.files.position());
+            let mut since = Instant::now();
+            while !progress.bytes.is_finished() {
+                std::thread::sleep(Duration::from_secs(1));
+                // the file copied for the longest
+                let oldest = progress
+                    .started
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .min_by_key(|(_, started)| **started)
+                    .map(|(file, started)| (file.clone(), *started));
+                let Some((file, started)) = oldest else {
+                    since = Instant::now();
+                    continue;
+                };
+                let now = (progress.bytes.position(), progress.files.position());
+                if now != last {
+                    (last, since) = (now, Instant::now());
+                }
+                if let Some(stall) = stall.filter(|stall| since.elapsed() >= *stall) {
+                    let secs = stall.as_secs();
+                    return on_expired(format!("Transfer stalled, no byte moved for {secs}s"));
+                }
+                if let Some(limit) = file_timeout.filter(|limit| started.elapsed() >= *limit) {
+                    let secs = limit.as_secs();
+                    return on_expired(format!("Copying {file:?} took longer than {secs}s"));
+                }
+            }
+        });
+    }
+
+    pub fn finish(&self) {
+        self.bytes.finish();
+        self.files.finish_and_clear();
+    }
+}
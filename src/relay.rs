@@ -0,0 +1,81 @@
+use crate::cli::Cli;
+use crate::config::Config;
+use crate::manage::Location;
+use crate::output;
+use crate::session::split_remote;
+use crate::session::OpenOpts;
+use crate::session::Session;
+use crate::sync;
+use crate::walk;
+use crate::Direction;
+use anyhow::Context;
+use anyhow::Result;
+use std::path::PathBuf;
+
+/// Copy `source` of one remote host to `destination` on another, like `scp -3`
+///
+/// The source host is mounted as well, so its files stream through this
+/// machine into the destination mount without landing on the local disk
+pub fn run(
+    cli: &Cli,
+    config: &Config,
+    open_opts: &OpenOpts,
+    source: &str,
+    destination: &str,
+) -> Result<()> {
+    let (remote_host, remote_path) =
+        split_remote(destination).with_context(|| format!("{destination:?} is not remote"))?;
+    // the source stays mounted for as long as the session
+    let mut from = None;
+    let session = sync::connect(cli, remote_host, || {
+        let location = Location::open(source)?;
+        let session = connect(
+            open_opts,
+            config,
+            &location,
+            source,
+            remote_host,
+            remote_path,
+        );
+        from = Some(location);
+        session
+    })?;
+    output::field("engine", "sftp");
+    sync::run(cli, config, &session)
+}
+
+/// A session uploading the mounted `from`, the remote `source`, to
+/// `remote_path` on `remote_host`
+fn connect(
+    open_opts: &OpenOpts,
+    config: &Config,
+    from: &Location,
+    source: &str,
+    remote_host: &str,
+    remote_path: &str,
+) -> Result<Session> {
+    // the source is remote, walked over its mount
+    walk::gitignore_without_git(true);
+    let mut local_path = from
+        .mounted
+        .to_str()
+        .with_context(|| format!("Fail to mount {source:?}"))?
+        .to_owned();
+    // like rsync, `dir/` copies the contents of `dir`
+    if source.ends_with('/') {
+        local_path.push('/');
+    }
+    let relayed = format!("{}:{}", from.remote_host, from.remote.display());
+    output::field("relay", &relayed);
+    let mut session = Session::connect(
+        remote_host.to_owned(),
+        Some(remote_path),
+        local_path,
+        Direction::Upload,
+        open_opts,
+        config,
+    )?;
+    // the mount of the source is in a new temporary directory on every run
+    session.relayed = Some(PathBuf::from(relayed));
+    Ok(session)
+}
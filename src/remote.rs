@@ -0,0 +1,241 @@
+use crate::state;
+use anyhow::Context;
+use anyhow::Result;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Output;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+/// `-o Key=Value` options of `ssh` and `sshfs` runs, for one host or all
+static SSH_OPTIONS: Mutex<Vec<(Option<String>, String)>> = Mutex::new(Vec::new());
+
+/// Pass `-o option` to every later `ssh` and `sshfs` run, only those to
+/// `remote_host` when given; an option already set is not added again
+pub fn add_ssh_option(remote_host: Option<&str>, option: String) {
+    let entry = (remote_host.map(str::to_owned), option);
+    let mut options = SSH_OPTIONS.lock().unwrap();
+    if !options.contains(&entry) {
+        options.push(entry);
+    }
+}
+
+/// Forget the options set for `remote_host` alone, so a new session to it
+/// starts from those of its own profile
+pub fn clear_ssh_options(remote_host: &str) {
+    SSH_OPTIONS
+        .lock()
+        .unwrap()
+        .retain(|(host, _)| host.as_deref() != Some(remote_host));
+}
+
+/// The `-o` arguments set by `add_ssh_option` for `remote_host`
+pub fn ssh_options(remote_host: &str) -> Vec<String> {
+    SSH_OPTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(host, _)| host.as_deref().is_none_or(|host| host == remote_host))
+        .flat_map(|(_, option)| ["-o".to_owned(), option.clone()])
+        .collect()
+}
+
+/// The `ssh` processes of `run` calls in flight, with their host
+static RUNNING: Mutex<Vec<(u32, String)>> = Mutex::new(Vec::new());
+
+/// Terminate the `ssh` of every `run` to `remote_host` in flight, which then
+/// fails
+pub fn kill_running(remote_host: &str) {
+    for (pid, host) in RUNNING.lock().unwrap().iter() {
+        if host == remote_host {
+            unsafe { libc::kill(*pid as libc::pid_t, libc::SIGTERM) };
+        }
+    }
+}
+
+/// Seconds between keepalives of connections whose ssh config sets none
+const SERVER_ALIVE_INTERVAL: u64 = 15;
+
+/// Have `ssh` and `sshfs` send keepalives to `remote_host`, so firewalls don't
+/// drop its connections while we are busy hashing or walking a tree
+///
+/// A `ServerAliveInterval` of the ssh config, as `ssh -G` resolves it, wins
+pub fn keep_alive(remote_host: &str) -> Result<()> {
+    let out = ssh(remote_host)
+        .arg("-G")
+        .arg(remote_host)
+        .stdin(Stdio::null())
+        .output()
+        .context("Fail to execute `ssh`, maybe `ssh` not found ?")?;
+    let configured = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .any(|line| line.starts_with("serveraliveinterval ") && line != "serveraliveinterval 0");
+    if !configured {
+        add_ssh_option(
+            Some(remote_host),
+            format!("ServerAliveInterval={SERVER_ALIVE_INTERVAL}"),
+        );
+    }
+    Ok(())
+}
+
+/// `ssh` with the options set by `add_ssh_option` for `remote_host`
+fn ssh(remote_host: &str) -> Command {
+    let mut command = Command::new("ssh");
+    command.args(ssh_options(remote_host));
+    command
+}
+
+/// Single-quote `s` so that the remote shell passes it through untouched
+fn quote(s: &OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &b in s.as_bytes() {
+        if b == b'\'' {
+            quoted.extend_from_slice(br"'\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// A remote shell command line running `args`
+///
+/// Every argument is quoted, so paths are never interpreted by the remote shell.
+/// Remote commands must be built with this instead of `format!`
+pub fn shell<I, S>(args: I) -> OsString
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut line = Vec::new();
+    for arg in args {
+        if !line.is_empty() {
+            line.push(b' ');
+        }
+        line.extend(quote(arg.as_ref()));
+    }
+    OsString::from_vec(line)
+}
+
+/// Chain command lines built by `shell` with `&&`
+pub fn and<I>(commands: I) -> OsString
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut line = OsString::new();
+    for command in commands {
+        if !line.is_empty() {
+            line.push(" && ");
+        }
+        line.push(command);
+    }
+    line
+}
+
+/// `git -C dir args..` as a remote command line
+///
+/// LFS smudging is skipped, LFS content is uploaded from the local working tree
+pub fn git<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> OsString {
+    let mut argv = vec![
+        OsStr::new("env"),
+        OsStr::new("GIT_LFS_SKIP_SMUDGE=1"),
+        OsStr::new("git"),
+        OsStr::new("-C"),
+        dir.as_os_str(),
+    ];
+    argv.extend(args.iter().map(AsRef::as_ref));
+    shell(argv)
+}
+
+/// Run `command` on `remote_host` through `ssh`, feeding `input` to its stdin
+pub fn run(remote_host: &str, command: &OsStr, input: Option<&[u8]>) -> Result<Output> {
+    let mut child = ssh(remote_host)
+        .arg(remote_host)
+        .arg(command)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Fail to execute `ssh`, maybe `ssh` not found ?")?;
+    let pid = child.id();
+    RUNNING.lock().unwrap().push((pid, remote_host.to_owned()));
+    let output = (|| {
+        if let Some(input) = input {
+            // dropping stdin closes it, so the remote command sees EOF
+            child.stdin.take().unwrap().write_all(input)?;
+        }
+        child.wait_with_output()
+    })();
+    RUNNING
+        .lock()
+        .unwrap()
+        .retain(|(running, _)| *running != pid);
+    Ok(output?)
+}
+
+/// Like `run` without input, but through an ssh connection shared with the
+/// previous and next calls for a minute, for snappy completion
+pub fn run_shared(remote_host: &str, command: &OsStr) -> Result<Output> {
+    let cache = state::cache_dir();
+    std::fs::create_dir_all(&cache)?;
+    let mut control_path = OsString::from("ControlPath=");
+    control_path.push(cache.join("ssh-%C"));
+    ssh(remote_host)
+        .args(["-o", "ControlMaster=auto", "-o", "ControlPersist=60"])
+        .arg("-o")
+        .arg(control_path)
+        .arg(remote_host)
+        .arg(command)
+        .stdin(Stdio::null())
+        .output()
+        .context("Fail to execute `ssh`, maybe `ssh` not found ?")
+}
+
+/// Where `smartscp daemon` keeps its master connections, `%C` standing for a
+/// hash of the host, port and user that `ssh` fills in
+pub fn daemon_control_path() -> PathBuf {
+    state::cache_dir().join("daemon-%C")
+}
+
+/// A master connection to `remote_host` for `smartscp daemon`, running until
+/// killed, which later `ssh` runs with its control path go through
+pub fn master(remote_host: &str) -> Result<Child> {
+    let mut control_path = OsString::from("ControlPath=");
+    control_path.push(daemon_control_path());
+    ssh(remote_host)
+        .args(["-N", "-o", "ControlMaster=yes", "-o"])
+        .arg(control_path)
+        .arg(remote_host)
+        .stdin(Stdio::null())
+        .spawn()
+        .context("Fail to execute `ssh`, maybe `ssh` not found ?")
+}
+
+/// Run `command` on `remote_host` through `ssh`, connected to our own stdin,
+/// stdout and stderr
+pub fn stream(remote_host: &str, command: &OsStr) -> Result<ExitStatus> {
+    ssh(remote_host)
+        .arg(remote_host)
+        .arg(command)
+        .status()
+        .context("Fail to execute `ssh`, maybe `ssh` not found ?")
+}
+
+/// The trimmed stderr of a finished remote command, for error messages
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).trim().to_owned()
+}
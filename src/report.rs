@@ -0,0 +1,120 @@
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// What happened to each file of a run, for `--report`
+#[derive(Clone, Default)]
+pub struct Report {
+    files: Arc<Mutex<BTreeMap<PathBuf, Entry>>>,
+}
+
+#[derive(Default)]
+struct Entry {
+    /// while being copied
+    started: Option<Instant>,
+    record: Record,
+}
+
+#[derive(Serialize, Default)]
+struct Record {
+    status: &'static str,
+    bytes: u64,
+    /// seconds spent copying
+    duration: f64,
+    retries: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Report {
+    /// `file` is being copied
+    pub fn start(&self, file: &Path) {
+        let mut files = self.files.lock().unwrap();
+        files.entry(file.to_path_buf()).or_default().started = Some(Instant::now());
+    }
+
+    /// `file` ended up `status`, like `copied` or `unchanged`, after `bytes`
+    pub fn done(&self, file: &Path, status: &'static str, bytes: u64) {
+        let mut files = self.files.lock().unwrap();
+        let entry = files.entry(file.to_path_buf()).or_default();
+        if let Some(started) = entry.started.take() {
+            entry.record.duration = started.elapsed().as_secs_f64();
+        }
+        entry.record.status = status;
+        entry.record.bytes = bytes;
+    }
+
+    /// `files` are copied again after losing the connection
+    pub fn retried(&self, files: &[PathBuf]) {
+        let mut records = self.files.lock().unwrap();
+        for file in files {
+            records.entry(file.clone()).or_default().record.retries += 1;
+        }
+    }
+
+    /// The files being copied failed with `error`
+    pub fn fail(&self, error: &anyhow::Error) {
+        for entry in self.files.lock().unwrap().values_mut() {
+            if let Some(started) = entry.started.take() {
+                entry.record.duration = started.elapsed().as_secs_f64();
+                entry.record.status = "failed";
+                entry.record.error = Some(format!("{error:#}"));
+            }
+        }
+    }
+
+    /// How many files ended up in each status, and their bytes
+    pub fn totals(&self) -> BTreeMap<&'static str, (u64, u64)> {
+        let mut totals: BTreeMap<&'static str, (u64, u64)> = BTreeMap::new();
+        for entry in self.files.lock().unwrap().values() {
+            let total = totals.entry(entry.record.status).or_default();
+            total.0 += 1;
+            total.1 += entry.record.bytes;
+        }
+        totals
+    }
+
+    /// Write one record per file to `path`, as CSV when it ends with `.csv` and
+    /// as json otherwise
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let files = self.files.lock().unwrap();
+        let contents = if path.extension().is_some_and(|e| e == "csv") {
+            let mut csv = String::from("file,status,bytes,duration,retries,error\n");
+            for (file, entry) in files.iter() {
+                let record = &entry.record;
+                csv.push_str(&format!(
+                    "{},{},{},{:.3},{},{}\n",
+                    csv_field(&file.to_string_lossy()),
+                    record.status,
+                    record.bytes,
+                    record.duration,
+                    record.retries,
+                    csv_field(record.error.as_deref().unwrap_or_default())
+                ));
+            }
+            csv.into_bytes()
+        } else {
+            let records: BTreeMap<&PathBuf, &Record> = files
+                .iter()
+                .map(|(file, entry)| (file, &entry.record))
+                .collect();
+            serde_json::to_vec_pretty(&records)?
+        };
+        std::fs::write(path, contents).with_context(|| format!("Fail to write {path:?}"))
+    }
+}
+
+/// `field` quoted when needed, doubling its quotes
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
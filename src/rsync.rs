@@ -0,0 +1,110 @@
+use crate::remote;
+use crate::session::Session;
+use crate::transfer::CopyOpts;
+use crate::walk;
+use crate::Direction;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Copy `files` of the source of `session` to its destination with `rsync`,
+/// over ssh with the same options as the mount
+///
+/// Only `no_clobber`, `partial_dir`, `numeric_ids`, `perms_umask` and `no_perms`
+/// of `copy_opts` apply, rsync resumes by itself
+pub fn copy(session: &Session, files: &[PathBuf], copy_opts: &CopyOpts) -> Result<()> {
+    let location = |path: &Path, remote: bool| -> Result<OsString> {
+        if !remote {
+            return Ok(path.as_os_str().to_owned());
+        }
+        let mut location = OsString::from(format!("{}:", session.remote_host));
+        location.push(session.remote_path(path)?);
+        Ok(location)
+    };
+    let upload = matches!(session.direction, Direction::Upload);
+    let mut source = location(&session.source, !upload)?;
+    let mut dest = location(&session.dest, upload)?;
+
+    let mut ssh = vec![OsString::from("ssh")];
+    ssh.extend(
+        remote::ssh_options(&session.remote_host)
+            .into_iter()
+            .map(OsString::from),
+    );
+    let mut command = Command::new("rsync");
+    command
+        .args(["--archive", "--protect-args", "--partial"])
+        .arg("--rsh")
+        .arg(remote::shell(ssh));
+    if let Some(partial_dir) = &copy_opts.partial_dir {
+        let mut arg = OsString::from("--partial-dir=");
+        arg.push(partial_dir);
+        command.arg(arg);
+    }
+    if let Some(mask) = copy_opts.perms_umask.filter(|mask| mask & 0o777 != 0) {
+        command.arg(format!("--chmod={}", chmod(mask)));
+    }
+    if copy_opts.no_perms {
+        command.arg("--no-perms");
+    }
+    if copy_opts.numeric_ids {
+        command.arg("--numeric-ids");
+    }
+    if walk::following() {
+        command.arg("--copy-links");
+    }
+    if copy_opts.no_clobber {
+        command.arg("--ignore-existing");
+    }
+    // a list of files only works relative to a directory
+    let list = session.source.is_dir();
+    if list {
+        command.args(["--from0", "--files-from=-"]);
+        source.push("/");
+        dest.push("/");
+    }
+    let mut child = command
+        .arg("--")
+        .arg(source)
+        .arg(dest)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Fail to execute `rsync`, maybe `rsync` not found ?")?;
+    let mut stdin = child.stdin.take().unwrap();
+    if list {
+        for file in files {
+            stdin.write_all(file.as_os_str().as_bytes())?;
+            stdin.write_all(b"\0")?;
+        }
+    }
+    drop(stdin);
+    if !child.wait()?.success() {
+        bail!("`rsync` failed");
+    }
+    Ok(())
+}
+
+/// The rsync `--chmod` rules clearing the bits of `mask` from files, like
+/// `Fg-w,Fo-w` for `022`
+fn chmod(mask: u32) -> String {
+    let mut rules = Vec::new();
+    for (who, shift) in [('u', 6), ('g', 3), ('o', 0)] {
+        let bits = (mask >> shift) & 0o7;
+        let perms: String = [('r', 0o4), ('w', 0o2), ('x', 0o1)]
+            .iter()
+            .filter(|(_, bit)| bits & bit != 0)
+            .map(|(c, _)| *c)
+            .collect();
+        if !perms.is_empty() {
+            rules.push(format!("F{who}-{perms}"));
+        }
+    }
+    rules.join(",")
+}
@@ -0,0 +1,280 @@
+use crate::cli::Cli;
+use crate::cli::LocalIo;
+use crate::cli::ReflinkMode;
+use crate::config::Config;
+use crate::output;
+use crate::remote;
+use crate::transfer::join;
+use crate::walk;
+use crate::Direction;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use log::warn;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Copy with the legacy scp protocol, for hosts offering no sftp subsystem
+///
+/// Uploads still skip git-ignored and excluded files, but downloads copy
+/// everything
+pub fn run(
+    cli: &Cli,
+    config: &Config,
+    remote_host: &str,
+    remote_path: Option<&str>,
+    local_path: &str,
+    direction: Direction,
+) -> Result<()> {
+    if let Some(flag) = mount_only(cli) {
+        bail!("{flag} needs the sshfs mount, the scp backend can't honor it");
+    }
+    output::field("engine", "scp");
+    let profile = config.profile(remote_host);
+    let defaults = config.default_excludes();
+    let excludes: Vec<&String> = defaults
+        .iter()
+        .chain(&config.excludes)
+        .chain(&profile.excludes)
+        .collect();
+    let remote_host = match &profile.user {
+        Some(user) if !remote_host.contains('@') => format!("{user}@{remote_host}"),
+        _ => remote_host.to_owned(),
+    };
+    let remote_host = remote_host.as_str();
+    let Some(remote_path) = remote_path else {
+        bail!("Give the remote path, it can't be inferred without sftp");
+    };
+    let remote_path = relative_to_home(remote_path);
+    let local_path = PathBuf::from(shellexpand::tilde(local_path).as_ref());
+    match direction {
+        Direction::Upload => upload(remote_host, &local_path, remote_path, &excludes),
+        Direction::Download => {
+            warn!("without sftp, git-ignored and excluded files are downloaded too");
+            let mut from = OsString::from(format!("{remote_host}:"));
+            from.push(remote::shell([remote_path]));
+            scp(remote_host, &[from, local_path.into_os_string()])
+        }
+    }
+}
+
+/// The first flag of `cli` which only a copy through the mount can honor,
+/// being read by `sync::run` alone
+pub fn mount_only(cli: &Cli) -> Option<&'static str> {
+    [
+        (cli.tracked_only, "--tracked-only"),
+        (cli.delete, "--delete"),
+        (cli.no_delete, "--no-delete"),
+        (cli.trash, "--trash"),
+        (cli.patch_mode, "--patch-mode"),
+        (cli.bundle, "--bundle"),
+        (cli.check_conflicts, "--check-conflicts"),
+        (cli.ignore_times, "--ignore-times"),
+        (cli.checksum, "--checksum"),
+        (cli.verify, "--verify"),
+        (cli.no_clobber, "--no-clobber"),
+        (cli.interactive, "--interactive"),
+        (cli.no_space_check, "--no-space-check"),
+        (cli.no_target_directory, "--no-target-directory"),
+        (cli.glob, "--glob"),
+        (cli.min_size.is_some(), "--min-size"),
+        (cli.max_size.is_some(), "--max-size"),
+        (cli.update, "--update"),
+        (cli.newer_than.is_some(), "--newer-than"),
+        (cli.pick, "--pick"),
+        (cli.link_dest.is_some(), "--link-dest"),
+        (cli.dedup, "--dedup"),
+        (cli.specials, "--specials"),
+        (cli.text_mode.is_some(), "--text-mode"),
+        (cli.connections.is_some(), "--connections"),
+        (cli.request_size.is_some(), "--request-size"),
+        (cli.auto_tune, "--auto-tune"),
+        (cli.local_io != LocalIo::Std, "--local-io"),
+        (cli.workers.is_some(), "--workers"),
+        (cli.block_size.is_some(), "--block-size"),
+        (cli.driver.is_some(), "--driver"),
+        (!matches!(cli.reflink, ReflinkMode::Auto), "--reflink"),
+        (cli.no_fsync, "--no-fsync"),
+        (cli.no_perms, "--no-perms"),
+        (cli.stall_timeout.is_some(), "--stall-timeout"),
+        (cli.timeout.is_some(), "--timeout"),
+        (cli.file_timeout.is_some(), "--file-timeout"),
+        (cli.wait_lock, "--wait-lock"),
+        (cli.move_source, "--move"),
+        (cli.no_resume, "--no-resume"),
+        (cli.partial_dir.is_some(), "--partial-dir"),
+        (cli.report.is_some(), "--report"),
+        (cli.metrics_file.is_some(), "--metrics-file"),
+        (cli.pushgateway.is_some(), "--pushgateway"),
+        (cli.files_from.is_some(), "--files-from"),
+        (cli.filter_file.is_some(), "--filter-file"),
+        (cli.no_hidden, "--no-hidden"),
+        (cli.safe_links, "--safe-links"),
+        (cli.numeric_ids, "--numeric-ids"),
+        (cli.perms_umask.is_some(), "--perms-umask"),
+    ]
+    .into_iter()
+    .find_map(|(set, flag)| set.then_some(flag))
+}
+
+/// Upload the files of `source` but `excludes`, like `cp -r` into `remote_path`
+fn upload(remote_host: &str, source: &Path, remote_path: &str, excludes: &[&String]) -> Result<()> {
+    let mut files = walk::files(source)?;
+    if !excludes.is_empty() {
+        files = walk::excluding(source, files, excludes)?;
+    }
+    let remote_path = Path::new(remote_path);
+    // like rsync, `dir/` copies the contents of `dir`
+    let contents_only = source.as_os_str().to_string_lossy().ends_with('/');
+    let into_dir = remote::run(
+        remote_host,
+        &remote::shell([
+            OsStr::new("test"),
+            OsStr::new("-d"),
+            remote_path.as_os_str(),
+        ]),
+        None,
+    )?
+    .status
+    .success();
+    let base = match source.file_name() {
+        Some(name) if into_dir && !contents_only => remote_path.join(name),
+        _ => remote_path.to_path_buf(),
+    };
+
+    // one `scp` per directory
+    let mut dirs: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        dirs.entry(dir).or_default().push(file);
+    }
+    if source.is_dir() {
+        let mkdir = dirs.keys().map(|dir| {
+            remote::shell([
+                OsStr::new("mkdir"),
+                OsStr::new("-p"),
+                OsStr::new("--"),
+                join(&base, dir).as_os_str(),
+            ])
+        });
+        let out = remote::run(remote_host, &remote::and(mkdir.collect::<Vec<_>>()), None)?;
+        if !out.status.success() {
+            bail!(
+                "Fail to create remote directories: {}",
+                remote::stderr(&out)
+            );
+        }
+    }
+    for (dir, files) in &dirs {
+        let mut args: Vec<OsString> = files
+            .iter()
+            .map(|file| join(source, file).into_os_string())
+            .collect();
+        let mut to = OsString::from(format!("{remote_host}:"));
+        if source.is_dir() {
+            to.push(remote::shell([join(&base, dir)]));
+            to.push("/");
+        } else {
+            to.push(remote::shell([&base]));
+        }
+        args.push(to);
+        scp(remote_host, &args)?;
+    }
+    Ok(())
+}
+
+/// Run `scp -O -p -r args..` to or from `remote_host`, the remote paths of
+/// `args` quoted for the remote shell the legacy protocol goes through
+fn scp(remote_host: &str, args: &[OsString]) -> Result<()> {
+    let status = Command::new("scp")
+        .args(["-O", "-p", "-r"])
+        .args(remote::ssh_options(remote_host))
+        .arg("--")
+        .args(args)
+        .status()
+        .context("Fail to execute `scp`, maybe `scp` not found ?")?;
+    if !status.success() {
+        bail!("`scp` failed");
+    }
+    Ok(())
+}
+
+/// `path` without a leading `~/`, which quoting would keep from expanding;
+/// scp starts relative paths from the home anyway
+fn relative_to_home(path: &str) -> &str {
+    match path {
+        "~" | "" => ".",
+        _ => path.strip_prefix("~/").unwrap_or(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use clap::Parser;
+
+    /// Flags scp copies honor, or which `main` and the logger read before
+    /// any copy
+    const HONORED: &[&str] = &[
+        "help",
+        "version",
+        "source",
+        "destination",
+        "to",
+        "hosts",
+        "from",
+        "backend",
+        "porcelain",
+        "color",
+        "log_target",
+        "log_file",
+        "verbose",
+        "follow_symlinks",
+        "no_default_excludes",
+        // only read along with a flag refused already
+        "metrics_job",
+        "from0",
+    ];
+
+    #[test]
+    fn mount_only_refuses_every_flag_of_sync() {
+        for arg in Cli::command().get_arguments() {
+            let id = arg.get_id().as_str();
+            if HONORED.contains(&id) {
+                continue;
+            }
+            let flag = format!("--{}", arg.get_long().unwrap());
+            let mut argv = vec![
+                "smartscp".to_owned(),
+                "dir".to_owned(),
+                "host:dir".to_owned(),
+            ];
+            argv.push(flag.clone());
+            if arg.get_action().takes_values() {
+                // a value other than the default, which any parser takes
+                let defaults: Vec<String> = arg
+                    .get_default_values()
+                    .iter()
+                    .map(|v| v.to_string_lossy().into_owned())
+                    .collect();
+                let value = arg
+                    .get_possible_values()
+                    .iter()
+                    .map(|v| v.get_name().to_owned())
+                    .find(|v| !defaults.contains(v))
+                    .unwrap_or_else(|| "022".to_owned());
+                argv.push(value);
+            }
+            if id == "trash" {
+                argv.push("--delete".to_owned());
+            }
+            let cli = Cli::try_parse_from(&argv).unwrap_or_else(|e| panic!("{argv:?}: {e}"));
+            assert!(mount_only(&cli).is_some(), "{flag} is not refused");
+        }
+    }
+}
@@ -0,0 +1,503 @@
+use crate::config::Config;
+use crate::config::Profile;
+use crate::get_remote_host;
+use crate::mount::Mount;
+use crate::output;
+use crate::remote;
+use crate::transfer;
+use crate::walk;
+use crate::Connection;
+use crate::Direction;
+use crate::PathProvenance;
+use anyhow::bail;
+use anyhow::Result;
+use std::path::Path;
+use std::path::PathBuf;
+use time::OffsetDateTime;
+
+/// Both ends of a transfer, the remote one reachable through an sshfs mount of
+/// the remote `/`
+pub struct Session {
+    pub remote_host: String,
+    pub remote_home: Option<String>,
+    pub direction: Direction,
+    pub source: PathBuf,
+    /// where the source itself ends up, resolved like `cp -r`
+    pub dest: PathBuf,
+    /// only copy what matches this glob, relative to `source`
+    pub pattern: Option<String>,
+    /// the config file section of `remote_host`
+    pub profile: Profile,
+    /// the remote runs Windows: no POSIX permissions nor shell
+    pub windows: bool,
+    /// the remote location a relay copies from, whose mount is the local end
+    pub relayed: Option<PathBuf>,
+    mount: Mount,
+}
+
+/// How `Session::open` reads the command line locations
+#[derive(Default)]
+pub struct OpenOpts {
+    /// The destination is the exact target name, never a directory to copy into
+    pub no_target_directory: bool,
+    /// Expand a local source glob, remote ones are always expanded
+    pub glob: bool,
+    /// An inferred remote path is the target itself, for --patch-mode whose
+    /// remote repo mirrors the local one
+    pub inferred_is_target: bool,
+}
+
+impl Session {
+    /// Parse the two command line locations and mount the remote side
+    pub fn open(
+        arg1: String,
+        arg2: String,
+        open_opts: &OpenOpts,
+        config: &Config,
+    ) -> Result<Session> {
+        let (remote_host, remote_path, local_path, direction) = locations(arg1, arg2)?;
+        Session::connect(
+            remote_host,
+            remote_path.as_deref(),
+            local_path,
+            direction,
+            open_opts,
+            config,
+        )
+    }
+
+    /// Mount `remote_host` and pair `remote_path` (inferred from `local_path`
+    /// when missing) with `local_path`, both taken literally
+    pub fn connect(
+        remote_host: String,
+        remote_path: Option<&str>,
+        local_path: String,
+        direction: Direction,
+        open_opts: &OpenOpts,
+        config: &Config,
+    ) -> Result<Session> {
+        let profile = config.profile(&remote_host);
+        let remote_host = match &profile.user {
+            Some(user) if !remote_host.contains('@') => format!("{user}@{remote_host}"),
+            _ => remote_host,
+        };
+        remote::clear_ssh_options(&remote_host);
+        if let Some(identity) = &profile.identity {
+            let identity = shellexpand::tilde(identity);
+            remote::add_ssh_option(Some(&remote_host), format!("IdentityFile={identity}"));
+        }
+        remote::keep_alive(&remote_host)?;
+        let host_params = get_remote_host(&remote_host).unwrap();
+
+        // placeholders, like `logs/%h-%d.log`
+        let host = remote_host.rsplit('@').next().unwrap_or_default();
+        let user = match &host_params.user {
+            Some(user) => user.clone(),
+            None => std::env::var("USER").unwrap_or_default(),
+        };
+        let remote_path = remote_path.map(|path| from_home(&expand_vars(path, host, &user)));
+        let remote_path = remote_path.as_deref();
+        let local_path = expand_vars(&local_path, host, &user);
+
+        // a remote glob is expanded over the mount, not by a remote shell
+        let (remote_path, pattern) = match (&direction, remote_path.and_then(split_glob)) {
+            (Direction::Download, Some((base, pattern))) => (Some(base), Some(pattern.to_owned())),
+            _ => (remote_path, None),
+        };
+        // a local one only with --glob, when the shell didn't expand it
+        let (local_path, pattern) = match (&direction, split_glob(&local_path)) {
+            (Direction::Upload, Some((base, local_pattern))) if open_opts.glob => {
+                let base = if base.is_empty() { "." } else { base };
+                (base.to_owned(), Some(local_pattern.to_owned()))
+            }
+            _ => (local_path, pattern),
+        };
+
+        let mount = Mount::new(&remote_host)?;
+        // the rules are read over the mount, no remote git is involved
+        if matches!(direction, Direction::Download) {
+            walk::gitignore_without_git(true);
+        }
+        let windows = mount.is_windows();
+        let remote_home = host_params.user.map(|u| {
+            if windows {
+                format!("/C:/Users/{u}")
+            } else {
+                format!("/home/{u}")
+            }
+        });
+        // `C:/Users/me` is `/C:/Users/me` over sftp
+        let remote_path = remote_path.map(|path| {
+            if windows {
+                windows_path(path)
+            } else {
+                path.to_owned()
+            }
+        });
+        let remote_path = remote_path.as_deref();
+        let remote_dir = profile.remote_dir.as_ref().map(|dir| {
+            let dir = shellexpand::tilde_with_context(dir, || remote_home.clone());
+            if windows {
+                windows_path(&dir)
+            } else {
+                dir.into_owned()
+            }
+        });
+        let connection = Connection::new(
+            remote_path,
+            &local_path,
+            remote_home.clone(),
+            remote_dir,
+            direction,
+        )?;
+        let remote_mounted = mount.mounted(&connection.remote_path);
+        // walking the local path would walk the remote `/`
+        let raw_mount_dir = mount.mounted(Path::new("/"));
+        let mount_dir = raw_mount_dir
+            .canonicalize()
+            .unwrap_or_else(|_| raw_mount_dir.clone());
+        if connection.local_path.starts_with(&mount_dir) && connection.local_path.is_dir() {
+            bail!(
+                "{:?} is inside the sshfs mount {mount_dir:?}",
+                connection.local_path
+            );
+        }
+        if mount_dir.starts_with(&connection.local_path) {
+            walk::skip(&raw_mount_dir);
+            walk::skip(&mount_dir);
+        }
+
+        match direction {
+            Direction::Upload => {
+                output::event("local", &[&connection.local_path]);
+                output::event("remote", &[connection.remote_path.as_path()]);
+            }
+            Direction::Download => {
+                output::event("remote", &[connection.remote_path.as_path()]);
+                output::event("local", &[&connection.local_path]);
+            }
+        }
+
+        // like rsync, `dir/` copies the contents of `dir` rather than `dir` itself
+        let raw_source = match direction {
+            Direction::Upload => Some(local_path.as_str()),
+            Direction::Download => remote_path,
+        };
+        let contents_only = raw_source.is_some_and(|x| x.ends_with('/'));
+        // matches of a glob are copied into the destination
+        let no_target_directory = open_opts.no_target_directory
+            || pattern.is_some()
+            || contents_only
+            || (open_opts.inferred_is_target
+                && matches!(connection.remote_path, PathProvenance::Inferred(_)));
+        let (source, dest): (PathBuf, PathBuf) = match direction {
+            Direction::Upload => (connection.local_path, remote_mounted),
+            Direction::Download => (remote_mounted, connection.local_path),
+        };
+        let dest = transfer::target_base(&source, &dest, no_target_directory)?;
+        // remote paths as the user wrote them rather than under the mount
+        let shown = |path: &Path| match mount.remote_path(path) {
+            Ok(remote) => format!("{remote_host}:{}", remote.display()),
+            Err(_) => format!("{path:?}"),
+        };
+        check_types(&source, &dest, no_target_directory, shown)?;
+        // the missing parents of a local destination, now that it fits
+        if let (Direction::Download, Some(parent)) = (direction, dest.parent()) {
+            transfer::mkdirs(parent)?;
+        }
+
+        Ok(Session {
+            remote_host,
+            remote_home,
+            direction,
+            source,
+            dest,
+            pattern,
+            profile,
+            windows,
+            relayed: None,
+            mount,
+        })
+    }
+
+    /// Where the remote `path` is found under the mount
+    pub fn mounted(&self, path: &Path) -> PathBuf {
+        self.mount.mounted(path)
+    }
+
+    /// The remote path of `mounted`, a path under the mount
+    pub fn remote_path(&self, mounted: &Path) -> Result<PathBuf> {
+        self.mount.remote_path(mounted)
+    }
+
+    /// The local end of the transfer, source or destination
+    pub fn local_root(&self) -> &Path {
+        match self.direction {
+            Direction::Upload => &self.source,
+            Direction::Download => &self.dest,
+        }
+    }
+
+    /// The remote end of the transfer, as seen by the remote host
+    pub fn remote_root(&self) -> Result<PathBuf> {
+        match self.direction {
+            Direction::Upload => self.remote_path(&self.dest),
+            Direction::Download => self.remote_path(&self.source),
+        }
+    }
+
+    /// The local end as state and locks know it, the relayed remote location
+    /// rather than its mount in a temporary directory
+    pub fn local_key(&self) -> &Path {
+        self.relayed.as_deref().unwrap_or(self.local_root())
+    }
+}
+
+/// Fail early when `source` can't be copied to `dest`, a directory onto a file
+/// or a file onto a directory, with both paths as `shown` and what to do instead
+fn check_types(
+    source: &Path,
+    dest: &Path,
+    no_target_directory: bool,
+    shown: impl Fn(&Path) -> String,
+) -> Result<()> {
+    let Ok(source_metadata) = walk::stat(source) else {
+        bail!("{} does not exist", shown(source));
+    };
+    // a parent which is a file stops the copy as surely as the target itself
+    let blocker = dest
+        .ancestors()
+        .find(|x| x.symlink_metadata().is_ok())
+        .filter(|x| *x != dest && !x.is_dir());
+    if let Some(blocker) = blocker {
+        bail!(
+            "Can't copy {} to {}: {} is a {}, not a directory, choose another destination",
+            shown(source),
+            shown(dest),
+            shown(blocker),
+            kind(blocker)
+        );
+    }
+    let Ok(dest_metadata) = dest.symlink_metadata() else {
+        return Ok(());
+    };
+    if source_metadata.is_dir() && !dest.is_dir() {
+        bail!(
+            "Can't copy the directory {} onto the {} {}: remove it first or choose another destination name",
+            shown(source),
+            kind(dest),
+            shown(dest)
+        );
+    }
+    if !source_metadata.is_dir() && dest_metadata.is_dir() {
+        let advice = if no_target_directory {
+            "to copy into it drop -T and any trailing slash of the source, or choose another destination name"
+        } else {
+            "choose another destination name"
+        };
+        bail!(
+            "Can't copy the {} {} onto the directory {}: {advice}",
+            kind(source),
+            shown(source),
+            shown(dest)
+        );
+    }
+    Ok(())
+}
+
+/// What `path` is, as error messages name it
+fn kind(path: &Path) -> &'static str {
+    match walk::stat(path) {
+        Ok(metadata) if metadata.is_dir() => "directory",
+        Ok(metadata) if metadata.is_symlink() => "symlink",
+        Ok(metadata) if metadata.is_file() => "file",
+        Ok(_) => "special file",
+        Err(_) => "missing file",
+    }
+}
+
+/// The remote host, remote path, local path and direction of two command line
+/// locations
+pub fn locations(
+    arg1: String,
+    arg2: String,
+) -> Result<(String, Option<String>, String, Direction)> {
+    let owned = |(remote_host, remote_path): (&str, &str)| {
+        (remote_host.to_owned(), Some(remote_path.to_owned()))
+    };
+    match (split_remote(&arg1), split_remote(&arg2)) {
+        (None, None) => {
+            // scp local_path remote-host
+            // ok
+            Ok((arg2, None, arg1, Direction::Upload))
+        }
+        (None, Some(remote)) => {
+            // scp local_path remote-host:remote-path
+            // ok
+            let (remote_host, remote_path) = owned(remote);
+            Ok((remote_host, remote_path, arg1, Direction::Upload))
+        }
+        (Some(remote), None) => {
+            // scp remote-host:remote-path local_path
+            // ok
+            let (remote_host, remote_path) = owned(remote);
+            Ok((remote_host, remote_path, arg2, Direction::Download))
+        }
+        (Some(_), Some(_)) => {
+            bail!(
+                "Both {arg1:?} and {arg2:?} look remote, write a local file name containing ':' as `./{arg1}`, or use --to/--from"
+            )
+        }
+    }
+}
+
+/// Split a `remote-host:path` location, like scp a ':' after a '/' is part of
+/// a local file name
+pub fn split_remote(arg: &str) -> Option<(&str, &str)> {
+    let (host, path) = arg.split_once(':')?;
+    if host.is_empty() || host.contains('/') {
+        return None;
+    }
+    Some((host, path))
+}
+
+/// A remote `path` rooted at `~` when relative, since like with scp a relative
+/// remote path starts from the home
+fn from_home(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    if path.is_empty() {
+        "~".to_owned()
+    } else if path.starts_with(['/', '~']) || drive {
+        path.to_owned()
+    } else {
+        format!("~/{path}")
+    }
+}
+
+/// A Windows `path` like `C:\Users\me` as sftp names it, `/C:/Users/me`
+fn windows_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let bytes = path.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        format!("/{path}")
+    } else {
+        path
+    }
+}
+
+/// `path` with `%h` replaced by the remote host, `%u` by the remote user, `%d`
+/// by today's date and `%%` by `%`
+fn expand_vars(path: &str, host: &str, user: &str) -> String {
+    let mut expanded = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => expanded.push_str(host),
+            Some('u') => expanded.push_str(user),
+            Some('d') => expanded.push_str(&today()),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+/// Like `2024-01-31`, in local time
+fn today() -> String {
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    let date = now.date();
+    format!(
+        "{}-{:02}-{:02}",
+        date.year(),
+        u8::from(date.month()),
+        date.day()
+    )
+}
+
+/// Split `path` before its first component with glob characters, into the
+/// directory to expand the glob in and the glob itself
+fn split_glob(path: &str) -> Option<(&str, &str)> {
+    let mut start = 0;
+    for component in path.split('/') {
+        if component.contains(['*', '?', '[', '{']) {
+            let base = &path[..start];
+            let base = if base.len() > 1 {
+                base.trim_end_matches('/')
+            } else {
+                base
+            };
+            return Some((base, &path[start..]));
+        }
+        start += component.len() + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(source: &Path, dest: &Path) -> Result<()> {
+        check_types(source, dest, false, |path| format!("{path:?}"))
+    }
+
+    #[test]
+    fn check_types_refuses_mismatched_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let (file, sub) = (dir.path().join("file"), dir.path().join("dir"));
+        std::fs::write(&file, "").unwrap();
+        std::fs::create_dir(&sub).unwrap();
+
+        check(&file, &dir.path().join("new")).unwrap();
+        check(&sub, &dir.path().join("new")).unwrap();
+        check(&file, &file).unwrap();
+        check(&sub, &sub).unwrap();
+        assert!(check(&sub, &file).is_err());
+        assert!(check(&file, &sub).is_err());
+        assert!(check(&file, &file.join("below")).is_err());
+        assert!(check(&dir.path().join("missing"), &sub).is_err());
+    }
+
+    #[test]
+    fn splits_remote_locations() {
+        assert_eq!(split_remote("host:path"), Some(("host", "path")));
+        assert_eq!(split_remote("user@host:"), Some(("user@host", "")));
+        assert_eq!(
+            split_remote("file:with:colon"),
+            Some(("file", "with:colon"))
+        );
+        assert_eq!(split_remote("./dir:x"), None);
+        assert_eq!(split_remote(":path"), None);
+        assert_eq!(split_remote("local"), None);
+    }
+
+    #[test]
+    fn roots_relative_remote_paths_at_home() {
+        assert_eq!(from_home(""), "~");
+        assert_eq!(from_home("logs"), "~/logs");
+        assert_eq!(from_home("/var/log"), "/var/log");
+        assert_eq!(from_home("~/logs"), "~/logs");
+        assert_eq!(from_home("~me/logs"), "~me/logs");
+        assert_eq!(from_home("C:/Users"), "C:/Users");
+        // the base a relative glob is expanded in
+        let path = from_home("logs/*.gz");
+        assert_eq!(split_glob(&path), Some(("~/logs", "*.gz")));
+        let path = from_home("*.gz");
+        assert_eq!(split_glob(&path), Some(("~", "*.gz")));
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        assert_eq!(expand_vars("logs/%h-%u", "web1", "me"), "logs/web1-me");
+        assert_eq!(expand_vars("100%%", "web1", "me"), "100%");
+    }
+}
@@ -0,0 +1,60 @@
+use crate::transfer;
+use crate::walk;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use indicatif::HumanBytes;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Bytes available to us on the filesystem holding `path`
+///
+/// Through the sshfs mount this is answered by the remote `statvfs`
+pub fn available(path: &Path) -> Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Fail to query free space of {path:?}"));
+    }
+    // `u64` already on Linux, narrower on macOS
+    #[allow(clippy::unnecessary_cast)]
+    let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+    Ok(available)
+}
+
+/// Bail out early when copying `files` from `source` to `dest` would not fit
+///
+/// Files about to be overwritten only count for the bytes they grow by
+pub fn check(source: &Path, files: &[PathBuf], dest: &Path) -> Result<()> {
+    let mut needed = 0;
+    for file in files {
+        let size = walk::stat(&transfer::join(source, file))?.len();
+        let existing = transfer::join(dest, file)
+            .symlink_metadata()
+            .map_or(0, |m| m.len());
+        needed += size.saturating_sub(existing);
+    }
+    if needed == 0 {
+        return Ok(());
+    }
+    // `dest` may not exist yet
+    let mut dir = dest;
+    while !dir.exists() {
+        match dir.parent() {
+            Some(parent) => dir = parent,
+            None => return Ok(()),
+        }
+    }
+    let available = available(dir)?;
+    if needed > available {
+        bail!(
+            "Not enough space on {dir:?}: {} needed, {} available",
+            HumanBytes(needed),
+            HumanBytes(available)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use crate::remote;
+use crate::transfer;
+use crate::transfer::join;
+use crate::transfer::CopyOpts;
+use crate::walk;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use xcp::operations::StatSender;
+use xcp::operations::StatusUpdate;
+
+/// Zero blocks of this size are left as holes
+const BLOCK: usize = 64 * 1024;
+
+/// Those of `files` under the local `root` taking less room than their size
+pub fn local(root: &Path, files: &[PathBuf]) -> Result<HashSet<PathBuf>> {
+    let mut sparse = HashSet::new();
+    for file in files {
+        let metadata = walk::stat(&join(root, file))?;
+        if metadata.is_file() && metadata.blocks() * 512 < metadata.len() {
+            sparse.insert(file.clone());
+        }
+    }
+    Ok(sparse)
+}
+
+/// Files under `root` on `remote_host` taking less room than their size,
+/// found remotely since sshfs doesn't report allocated blocks
+pub fn remote(remote_host: &str, root: &Path) -> Result<HashSet<PathBuf>> {
+    let command = remote::shell([
+        OsStr::new("find"),
+        root.as_os_str(),
+        OsStr::new("-type"),
+        OsStr::new("f"),
+        OsStr::new("-printf"),
+        OsStr::new("%S\\t%P\\0"),
+    ]);
+    let out = remote::run(remote_host, &command, None)?;
+    if !out.status.success() {
+        bail!("Fail to find remote sparse files: {}", remote::stderr(&out));
+    }
+    let mut sparse = HashSet::new();
+    for record in out.stdout.split(|b| *b == 0) {
+        let Some(tab) = record.iter().position(|b| *b == b'\t') else {
+            continue;
+        };
+        let ratio: f64 = String::from_utf8_lossy(&record[..tab])
+            .parse()
+            .unwrap_or(1.0);
+        if ratio < 1.0 {
+            sparse.insert(PathBuf::from(OsStr::from_bytes(&record[tab + 1..])));
+        }
+    }
+    Ok(sparse)
+}
+
+/// Copy `from` to `to`, writing neither its holes nor its zero blocks so `to`
+/// is sparse too
+///
+/// Holes are found with SEEK_DATA/SEEK_HOLE where supported, zero blocks by
+/// reading them (sshfs reports no holes). Progress, holes included, goes to
+/// `stats`
+pub fn copy(from: &Path, to: &Path, stats: &StatSender, copy_opts: &CopyOpts) -> Result<()> {
+    let mut reader = File::open(from).with_context(|| format!("Fail to open {from:?}"))?;
+    let metadata = reader.metadata()?;
+    let len = metadata.len();
+    let mut writer = File::create(to).with_context(|| format!("Fail to create {to:?}"))?;
+    let mut buf = vec![0; BLOCK];
+    let mut offset = 0;
+    while offset < len {
+        let Some(data) = seek(&reader, offset, libc::SEEK_DATA) else {
+            // only a hole is left
+            break;
+        };
+        stats.send(StatusUpdate::Copied(data.saturating_sub(offset)))?;
+        let hole = match seek(&reader, data, libc::SEEK_HOLE) {
+            Some(hole) if hole > data => hole.min(len),
+            _ => len,
+        };
+        reader.seek(SeekFrom::Start(data))?;
+        let mut pos = data;
+        while pos < hole {
+            let n = (hole - pos).min(BLOCK as u64) as usize;
+            reader.read_exact(&mut buf[..n])?;
+            if buf[..n].iter().any(|b| *b != 0) {
+                writer.seek(SeekFrom::Start(pos))?;
+                writer.write_all(&buf[..n])?;
+            }
+            pos += n as u64;
+            stats.send(StatusUpdate::Copied(n as u64))?;
+        }
+        offset = hole;
+    }
+    stats.send(StatusUpdate::Copied(len.saturating_sub(offset)))?;
+    // a trailing hole
+    writer.set_len(len)?;
+    transfer::finish_copy(&writer, &metadata, to, copy_opts)
+}
+
+/// The next data (or hole) offset of `file` from `offset`, `offset` itself when
+/// the file system can't tell, None when only a hole follows
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> Option<u64> {
+    let found = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if found >= 0 {
+        return Some(found as u64);
+    }
+    match std::io::Error::last_os_error().raw_os_error() {
+        Some(libc::ENXIO) => None,
+        _ => Some(offset),
+    }
+}
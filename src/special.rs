@@ -0,0 +1,115 @@
+use crate::output;
+use crate::remote;
+use crate::session::Session;
+use crate::transfer::join;
+use crate::transfer::mkdirs;
+use crate::walk;
+use crate::Direction;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// How many FIFOs are created by one remote command
+const BATCH: usize = 256;
+
+/// Split `files` (relative to `source`) into the regular ones and the FIFOs,
+/// sockets and devices, which can't be copied by reading them
+pub fn split(source: &Path, files: Vec<PathBuf>) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut regular = Vec::new();
+    let mut specials = Vec::new();
+    for file in files {
+        let file_type = walk::stat(&join(source, &file))?.file_type();
+        if file_type.is_fifo()
+            || file_type.is_socket()
+            || file_type.is_block_device()
+            || file_type.is_char_device()
+        {
+            specials.push(file);
+        } else {
+            regular.push(file);
+        }
+    }
+    Ok((regular, specials))
+}
+
+/// Whether `file` under `source` is a FIFO
+pub fn is_fifo(source: &Path, file: &Path) -> bool {
+    join(source, file)
+        .symlink_metadata()
+        .is_ok_and(|m| m.file_type().is_fifo())
+}
+
+/// Create the `fifos` of the source of `session` on its destination, with the
+/// same permissions
+pub fn make_fifos(session: &Session, fifos: &[PathBuf]) -> Result<()> {
+    match session.direction {
+        Direction::Upload => {
+            let remote_dest = session.remote_path(&session.dest)?;
+            for batch in fifos.chunks(BATCH) {
+                let mut commands = Vec::new();
+                for fifo in batch {
+                    let mode = mode(&join(&session.source, fifo))?;
+                    let to = join(&remote_dest, fifo);
+                    if let Some(parent) = to.parent() {
+                        commands.push(remote::shell([
+                            OsStr::new("mkdir"),
+                            OsStr::new("-p"),
+                            OsStr::new("--"),
+                            parent.as_os_str(),
+                        ]));
+                    }
+                    commands.push(remote::shell([
+                        OsStr::new("rm"),
+                        OsStr::new("-f"),
+                        OsStr::new("--"),
+                        to.as_os_str(),
+                    ]));
+                    commands.push(remote::shell([
+                        OsStr::new("mkfifo"),
+                        OsStr::new("-m"),
+                        OsStr::new(&mode),
+                        OsStr::new("--"),
+                        to.as_os_str(),
+                    ]));
+                }
+                let out = remote::run(&session.remote_host, &remote::and(commands), None)?;
+                if !out.status.success() {
+                    bail!("Fail to create remote FIFOs: {}", remote::stderr(&out));
+                }
+            }
+        }
+        Direction::Download => {
+            for fifo in fifos {
+                let from = join(&session.source, fifo);
+                let to = join(&session.dest, fifo);
+                if let Some(parent) = to.parent() {
+                    mkdirs(parent)?;
+                }
+                let _ = std::fs::remove_file(&to);
+                let mode = from.symlink_metadata()?.permissions().mode() & 0o7777;
+                let path = CString::new(to.as_os_str().as_bytes())?;
+                if unsafe { libc::mkfifo(path.as_ptr(), mode as libc::mode_t) } != 0 {
+                    return Err(std::io::Error::last_os_error())
+                        .with_context(|| format!("Fail to create FIFO {to:?}"));
+                }
+            }
+        }
+    }
+    for fifo in fifos {
+        output::event("fifo", &[&join(&session.dest, fifo)]);
+    }
+    Ok(())
+}
+
+/// The permissions of `path` in octal, for `mkfifo -m`
+fn mode(path: &Path) -> Result<String> {
+    let mode = path.symlink_metadata()?.permissions().mode() & 0o7777;
+    Ok(format!("{mode:o}"))
+}
@@ -0,0 +1,183 @@
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// What a file looked like right after the last successful sync
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub size: u64,
+    /// seconds since the epoch, the precision sftp keeps
+    pub mtime: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl Record {
+    pub fn of(metadata: &Metadata) -> Result<Record> {
+        Ok(Record {
+            size: metadata.len(),
+            mtime: mtime_secs(metadata)?,
+            hash: None,
+        })
+    }
+
+    /// Whether `metadata` still describes the recorded file
+    pub fn matches(&self, metadata: &Metadata) -> Result<bool> {
+        Ok(self.size == metadata.len() && self.mtime == mtime_secs(metadata)?)
+    }
+}
+
+/// Seconds since the epoch, the precision sftp keeps
+pub fn mtime_secs(metadata: &Metadata) -> Result<u64> {
+    Ok(metadata
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()))
+}
+
+/// `~/.cache/smartscp`, or under `$XDG_CACHE_HOME` when set
+pub fn cache_dir() -> PathBuf {
+    let cache = match std::env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME").unwrap()).join(".cache"),
+    };
+    cache.join("smartscp")
+}
+
+/// A file name for the pair of `local` and `remote` on `remote_host`
+///
+/// Hashed, as the paths themselves may be longer than a file name can be
+fn key(remote_host: &str, local: &Path, remote: &Path) -> String {
+    let mut hasher = Sha256::new();
+    for part in [
+        remote_host.as_bytes(),
+        remote.as_os_str().as_bytes(),
+        local.as_os_str().as_bytes(),
+    ] {
+        // length-prefixed, so no two pairs hash the same bytes
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// The hash of the `git diff HEAD` the last patch mode run left in the remote
+/// repo at `remote_dir` on `remote_host`, if any
+pub fn patched(remote_host: &str, remote_dir: &Path) -> Result<Option<String>> {
+    let path = patched_path(remote_host, remote_dir);
+    match std::fs::read_to_string(&path) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Fail to read {path:?}")),
+    }
+}
+
+/// Remember `hash` as the `git diff HEAD` a patch left in the remote repo at
+/// `remote_dir` on `remote_host`, see `patched`
+pub fn set_patched(remote_host: &str, remote_dir: &Path, hash: &str) -> Result<()> {
+    let path = patched_path(remote_host, remote_dir);
+    std::fs::create_dir_all(cache_dir())?;
+    std::fs::write(&path, hash).with_context(|| format!("Fail to write {path:?}"))
+}
+
+fn patched_path(remote_host: &str, remote_dir: &Path) -> PathBuf {
+    cache_dir().join(format!(
+        "{}.patch",
+        key(remote_host, Path::new(""), remote_dir)
+    ))
+}
+
+/// An advisory lock on syncing a local with a remote directory, released on
+/// drop
+pub struct Lock {
+    _file: File,
+}
+
+/// Lock syncing `local` with `remote` on `remote_host` against other runs,
+/// waiting for them with `wait` or else failing
+///
+/// The lock file is named by the hashed `key`, like the sync state, so deep
+/// paths fit in a file name too
+pub fn lock(remote_host: &str, local: &Path, remote: &Path, wait: bool) -> Result<Lock> {
+    let dir = cache_dir();
+    std::fs::create_dir_all(&dir).with_context(|| format!("Fail to create {dir:?}"))?;
+    let path = dir.join(format!("{}.lock", key(remote_host, local, remote)));
+    let file = File::create(&path).with_context(|| format!("Fail to create {path:?}"))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        if !wait {
+            bail!("Another run is syncing {local:?} with {remote_host}:{remote:?}, use --wait-lock to wait for it");
+        }
+        info!("waiting for another run syncing {local:?} with {remote_host}:{remote:?}");
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Fail to lock {path:?}"));
+        }
+    }
+    Ok(Lock { _file: file })
+}
+
+/// Files synced between a local and a remote directory, as of the last
+/// successful run, kept under `~/.cache/smartscp`
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct State {
+    #[serde(skip)]
+    path: PathBuf,
+    files: BTreeMap<PathBuf, Record>,
+}
+
+impl State {
+    /// The state of syncing `local` with `remote` on `remote_host`, empty when
+    /// they were never synced
+    pub fn load(remote_host: &str, local: &Path, remote: &Path) -> Result<State> {
+        let path = cache_dir().join(format!("{}.json", key(remote_host, local, remote)));
+
+        let mut state: State = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Fail to parse sync state {path:?}"))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e).with_context(|| format!("Fail to read {path:?}")),
+        };
+        state.path = path;
+        Ok(state)
+    }
+
+    pub fn get(&self, file: &Path) -> Option<&Record> {
+        self.files.get(file)
+    }
+
+    /// Remember `file` as synced, looking like `metadata` and hashing to `hash`
+    /// when known
+    pub fn record(&mut self, file: &Path, metadata: &Metadata, hash: Option<String>) -> Result<()> {
+        let mut record = Record::of(metadata)?;
+        record.hash = hash;
+        self.files.insert(file.to_path_buf(), record);
+        Ok(())
+    }
+
+    /// Forget the files `keep` rejects, like those gone from the source
+    pub fn retain(&mut self, mut keep: impl FnMut(&Path) -> bool) {
+        self.files.retain(|file, _| keep(file));
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec(self)?)
+            .with_context(|| format!("Fail to write sync state {:?}", self.path))
+    }
+}
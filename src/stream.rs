@@ -0,0 +1,61 @@
+use crate::get_remote_host;
+use crate::manage::Location;
+use crate::remote;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use std::fs::File;
+
+/// Stream stdin into the file `remote_path` on `remote_host`, written through
+/// the sshfs mount like any copied file, without a temporary file
+pub fn upload(remote_host: &str, remote_path: &str) -> Result<()> {
+    if remote_path.is_empty() {
+        bail!("A remote file name is needed, like `{remote_host}:file`");
+    }
+    let location = Location::open(&format!("{remote_host}:{remote_path}"))?;
+    let remote = &location.remote;
+    let mut file = File::create(&location.mounted)
+        .with_context(|| format!("Fail to create {remote_host}:{remote:?}"))?;
+    std::io::copy(&mut std::io::stdin().lock(), &mut file)
+        .with_context(|| format!("Fail to write stdin to {remote_host}:{remote:?}"))?;
+    // errors of the last writes only show once flushed
+    file.sync_all()
+        .with_context(|| format!("Fail to write stdin to {remote_host}:{remote:?}"))
+}
+
+/// Stream the file `remote_path` on `remote_host` to stdout
+pub fn download(remote_host: &str, remote_path: &str) -> Result<()> {
+    let path = expand(remote_host, remote_path)?;
+    let status = remote::stream(remote_host, &remote::shell(["cat", "--", path.as_str()]))?;
+    if !status.success() {
+        bail!("Fail to read {remote_host}:{path}");
+    }
+    Ok(())
+}
+
+/// Print the last `lines` lines of `remote_path` on `remote_host`, then follow
+/// what is appended until interrupted, also across log rotation
+pub fn tail(remote_host: &str, remote_path: &str, lines: u64) -> Result<()> {
+    let path = expand(remote_host, remote_path)?;
+    let lines = lines.to_string();
+    let status = remote::stream(
+        remote_host,
+        &remote::shell(["tail", "-F", "-n", lines.as_str(), "--", path.as_str()]),
+    )?;
+    if !status.success() {
+        bail!("Fail to follow {remote_host}:{path}");
+    }
+    Ok(())
+}
+
+/// `remote_path` with `~` expanded to the remote home, relative paths are
+/// left to start from the home as ssh does
+fn expand(remote_host: &str, remote_path: &str) -> Result<String> {
+    if remote_path.is_empty() {
+        bail!("A remote file name is needed, like `{remote_host}:file`");
+    }
+    let remote_home = get_remote_host(remote_host)?
+        .user
+        .map(|u| format!("/home/{u}"));
+    Ok(shellexpand::tilde_with_context(remote_path, || remote_home).into_owned())
+}
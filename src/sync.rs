@@ -0,0 +1,817 @@
+use crate::bundle;
+use crate::checksum;
+use crate::cli::Backend;
+use crate::cli::Cli;
+use crate::cli::LocalIo;
+use crate::cli::ReflinkMode;
+use crate::config::Config;
+use crate::dedup;
+use crate::detect;
+use crate::filter::Filter;
+use crate::git;
+use crate::metrics;
+use crate::mount;
+use crate::patch;
+use crate::pick;
+use crate::progress::Progress;
+use crate::remote;
+use crate::report::Report;
+use crate::rsync;
+use crate::session::Session;
+use crate::space;
+use crate::sparse;
+use crate::special;
+use crate::state;
+use crate::state::State;
+use crate::text::Conversion;
+use crate::transfer;
+use crate::transfer::CopyOpts;
+use crate::trash;
+use crate::tune;
+use crate::tune::Tuning;
+use crate::walk;
+use crate::Direction;
+use anyhow::bail;
+use anyhow::Result;
+use crossbeam_channel as cbc;
+use log::error;
+use log::info;
+use log::warn;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use xcp::drivers::Drivers;
+use xcp::errors::XcpError;
+use xcp::operations::Reflink;
+use xcp::operations::StatSender;
+use xcp::operations::StatusUpdate;
+
+/// How many times copying goes on after losing the connection
+const RETRIES: usize = 3;
+
+/// How long sshfs gets to reconnect
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Why a watchdog cut the run short, unmounting the remote and killing the
+/// remote commands so that what hangs on them fails
+#[derive(Clone)]
+struct Abort {
+    reason: Arc<Mutex<Option<String>>>,
+    /// the run is over, nothing to cut short anymore
+    finished: Arc<AtomicBool>,
+    mount_dir: PathBuf,
+    remote_host: String,
+}
+
+impl Abort {
+    fn trigger(&self, reason: String) {
+        if self.finished.load(Ordering::Relaxed) {
+            return;
+        }
+        error!("{reason}");
+        *self.reason.lock().unwrap() = Some(reason);
+        mount::force_unmount(&self.mount_dir);
+        // commands of a remote shell don't go through the mount
+        remote::kill_running(&self.remote_host);
+    }
+
+    /// Fail with the reason, once triggered
+    fn check(&self) -> Result<()> {
+        match self.reason.lock().unwrap().clone() {
+            Some(reason) => bail!(reason),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Copy the source of `session` to its destination as `cli` asks, with the
+/// defaults of `config`, within `--timeout`
+pub fn run(cli: &Cli, config: &Config, session: &Session) -> Result<()> {
+    let abort = Abort {
+        reason: Arc::default(),
+        finished: Arc::default(),
+        mount_dir: session.mounted(Path::new("/")),
+        remote_host: session.remote_host.clone(),
+    };
+    if let Some(timeout) = cli.timeout {
+        let abort = abort.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(timeout));
+            abort.trigger(format!("Timed out after {timeout}s"));
+        });
+    }
+    let started = Instant::now();
+    let report = Report::default();
+    let copied = copy(cli, config, session, &abort, &report);
+    abort.finished.store(true, Ordering::Relaxed);
+    let result = abort.check().and(copied);
+    if let Err(e) = &result {
+        report.fail(e);
+    }
+    record(
+        cli,
+        &session.remote_host,
+        &report,
+        started.elapsed(),
+        result.is_ok(),
+    )?;
+    result
+}
+
+/// Write the `--report` and the metrics of a run to `remote_host`, also of one
+/// failing before a session was even open, so monitoring sees it failed
+pub fn record(
+    cli: &Cli,
+    remote_host: &str,
+    report: &Report,
+    duration: Duration,
+    success: bool,
+) -> Result<()> {
+    if let Some(path) = &cli.report {
+        report.save(&per_host(path, remote_host))?;
+    }
+    if cli.metrics_file.is_some() || cli.pushgateway.is_some() {
+        let text = metrics::text(remote_host, report, duration, success);
+        if let Some(path) = &cli.metrics_file {
+            metrics::write(&per_host(path, remote_host), &text)?;
+        }
+        if let Some(url) = &cli.pushgateway {
+            metrics::push(url, &cli.metrics_job, remote_host, &text)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs go to several hosts at once, see `several_hosts`
+static SEVERAL_HOSTS: AtomicBool = AtomicBool::new(false);
+
+/// The runs go to several hosts at once, so each writes its own `--report` and
+/// `--metrics-file` and labels its progress bars
+pub fn several_hosts() {
+    SEVERAL_HOSTS.store(true, Ordering::Relaxed);
+}
+
+/// `path` with `remote_host` before its extension, like `report.web1.csv`, when
+/// the runs go to several hosts at once
+fn per_host(path: &Path, remote_host: &str) -> PathBuf {
+    if !SEVERAL_HOSTS.load(Ordering::Relaxed) {
+        return path.to_owned();
+    }
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(remote_host);
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// The session opened by `connect`, whose failure is recorded by `record`
+pub fn connect(
+    cli: &Cli,
+    remote_host: &str,
+    connect: impl FnOnce() -> Result<Session>,
+) -> Result<Session> {
+    let started = Instant::now();
+    connect().inspect_err(|_| {
+        let recorded = record(
+            cli,
+            remote_host,
+            &Report::default(),
+            started.elapsed(),
+            false,
+        );
+        if let Err(e) = recorded {
+            error!("{e:#}");
+        }
+    })
+}
+
+fn copy(
+    cli: &Cli,
+    config: &Config,
+    session: &Session,
+    abort: &Abort,
+    report: &Report,
+) -> Result<()> {
+    let tuning = Tuning {
+        workers: cli.workers.or(config.workers).unwrap_or(4),
+        block_size: cli.block_size.or(config.block_size).unwrap_or(1048576),
+    };
+    let mut opts = xcp_opts(cli, config, session, tuning)?;
+
+    let progress = Progress::new();
+    if SEVERAL_HOSTS.load(Ordering::Relaxed) {
+        progress.label(&session.remote_host);
+    }
+    let (stat_tx, stat_rx) = cbc::unbounded();
+    let stats = StatSender::new(stat_tx, &opts);
+    // Gather the results as we go; the channel ends once every sender, handed
+    // to the driver calls, is dropped
+    let mut gather = Some({
+        let progress = progress.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for stat in stat_rx {
+                progress.update(&stat);
+                if let StatusUpdate::Error(e) = stat {
+                    // FIXME: Optional continue?
+                    error!("Received error: {}", e);
+                    return Err(e.into());
+                }
+            }
+            Ok(())
+        })
+    });
+
+    let source = session.source.as_path();
+    let dest = session.dest.as_path();
+    let remote_host = session.remote_host.as_str();
+
+    // Sanity-check the source up-front
+    if !source.exists() {
+        return Err(XcpError::InvalidSource("Source does not exist.").into());
+    }
+    if source == dest {
+        return Err(XcpError::InvalidSource("Cannot copy a directory into itself").into());
+    }
+
+    // runs syncing the same directories would interleave their writes
+    let _lock = state::lock(
+        remote_host,
+        session.local_key(),
+        &session.remote_root()?,
+        cli.wait_lock,
+    )?;
+
+    if (cli.tracked_only || cli.patch_mode || cli.bundle)
+        && (!matches!(session.direction, Direction::Upload) || !source.is_dir())
+    {
+        bail!(
+            "--tracked-only, --patch-mode and --bundle only support uploading a git working tree"
+        );
+    }
+
+    // these run commands in a remote POSIX shell
+    if session.windows
+        && (cli.patch_mode
+            || cli.bundle
+            || cli.checksum
+            || cli.verify
+            || cli.trash
+            || cli.dedup
+            || cli.specials
+            || cli.move_source
+            || cli.numeric_ids)
+    {
+        bail!("--patch-mode, --bundle, --checksum, --verify, --trash, --dedup, --specials, --move and --numeric-ids don't support Windows remotes");
+    }
+
+    if cli.backend == Backend::Scp {
+        bail!("--backend scp only copies between this machine and a single host");
+    }
+    if cli.backend == Backend::Rsync
+        && (session.windows || cli.interactive || cli.text_mode.is_some())
+    {
+        bail!("--backend rsync supports neither Windows remotes, --interactive nor --text-mode");
+    }
+    // fail before any work rather than halfway through
+    let mut needed = Vec::new();
+    if cli.backend == Backend::Rsync {
+        if !detect::found("rsync") {
+            bail!("--backend rsync needs `rsync` on this machine");
+        }
+        needed.push(("rsync", "--backend rsync"));
+    }
+    if cli.patch_mode || cli.bundle {
+        needed.push(("git", "--patch-mode and --bundle"));
+    }
+    if !needed.is_empty() {
+        let programs: Vec<&str> = needed.iter().map(|(program, _)| *program).collect();
+        let found = detect::remote_programs(remote_host, &programs)?;
+        info!("found {found:?} of {programs:?} on {remote_host}");
+        if let Some((missing, by)) = needed.iter().find(|(x, _)| !found.contains(x)) {
+            bail!("{by} needs `{missing}` on {remote_host}");
+        }
+    }
+
+    if cli.bundle {
+        let remote_dir = session.remote_path(dest)?;
+        bundle::sync_history(remote_host, &remote_dir, source, dest, cli.delete)?;
+    }
+
+    if cli.delete && !cli.tracked_only && !cli.patch_mode {
+        bail!("--delete needs --tracked-only or --patch-mode");
+    }
+    let trash = match (&session.remote_home, cli.trash) {
+        (Some(home), true) => {
+            let trash = trash::run_dir(Path::new(home));
+            Some(session.mounted(&trash))
+        }
+        (None, true) => bail!("--trash needs a known remote user"),
+        (_, false) => None,
+    };
+    let patched = if cli.patch_mode {
+        let remote_dir = session.remote_path(dest)?;
+        patch::upload(
+            remote_host,
+            &remote_dir,
+            &opts,
+            source,
+            dest,
+            stats.clone(),
+            cli.delete,
+            trash.as_deref(),
+        )?
+    } else {
+        false
+    };
+
+    if patched {
+        drop(stats);
+    } else {
+        // the browser of --pick has the terminal to itself
+        if !cli.pick {
+            progress.scanning(source);
+        }
+        // created like `cp -r` does, unless files are picked out of the tree
+        let mut empty_dirs = Vec::new();
+        let mut files = if let Some(list) = &cli.files_from {
+            walk::listed(source, list, cli.from0)?
+        } else if cli.tracked_only {
+            git::tracked_files(source)?
+        } else {
+            let files;
+            (files, empty_dirs) = walk::tree(source)?;
+            files
+        };
+        if session.pattern.is_some()
+            || cli.filter_file.is_some()
+            || cli.pick
+            || cli.min_size.is_some()
+            || cli.max_size.is_some()
+            || cli.newer_than.is_some()
+        {
+            empty_dirs.clear();
+        }
+        if let Some(pattern) = &session.pattern {
+            files = walk::matching(files, pattern)?;
+            if files.is_empty() {
+                bail!("No match for {pattern:?} in {source:?}");
+            }
+        }
+        // files named by a list or by git are wanted whatever they are called
+        let defaults = if cli.files_from.is_some() || cli.tracked_only || cli.patch_mode {
+            Vec::new()
+        } else {
+            config.default_excludes()
+        };
+        let excludes: Vec<&String> = defaults
+            .iter()
+            .chain(&config.excludes)
+            .chain(&session.profile.excludes)
+            .collect();
+        if !excludes.is_empty() {
+            files = walk::excluding(source, files, &excludes)?;
+            empty_dirs = walk::excluding_dirs(source, empty_dirs, &excludes)?;
+        }
+        if cli.safe_links {
+            files = walk::safe_links(source, files)?;
+        }
+        if cli.no_hidden {
+            files = walk::visible(files);
+            empty_dirs = walk::visible(empty_dirs);
+        }
+        if let Some(filter_file) = &cli.filter_file {
+            files = Filter::load(filter_file)?.apply(source, files)?;
+        }
+        if cli.pick {
+            if !source.is_dir() {
+                bail!("--pick needs a directory to pick from");
+            }
+            // quitting the browser copies nothing
+            let picked = pick::run(source, dest)?.unwrap_or_default();
+            files.retain(|file| picked.iter().any(|p| file.starts_with(p)));
+        }
+        if cli.min_size.is_some() || cli.max_size.is_some() {
+            files = walk::by_size(source, files, cli.min_size, cli.max_size)?;
+        }
+        if let Some(age) = cli.newer_than {
+            let since = SystemTime::now()
+                .checked_sub(age)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            files = walk::by_mtime(source, files, since)?;
+        }
+        let specials;
+        (files, specials) = special::split(source, files)?;
+        let (fifos, skipped_specials): (Vec<PathBuf>, Vec<PathBuf>) = specials
+            .into_iter()
+            .partition(|file| cli.specials && special::is_fifo(source, file));
+        if !skipped_specials.is_empty() {
+            for file in &skipped_specials {
+                info!("special file, skipped: {:?}", transfer::join(source, file));
+            }
+            warn!(
+                "Skipped {} special files, see --verbose; --specials recreates FIFOs",
+                skipped_specials.len()
+            );
+        }
+        let collisions = walk::case_collisions(&files);
+        if !collisions.is_empty() && walk::case_insensitive(dest)? {
+            for group in &collisions {
+                error!("only differ in case: {group:?}");
+            }
+            bail!(
+                "{} groups of files would overwrite each other on the case-insensitive {dest:?}, rename them or exclude some",
+                collisions.len()
+            );
+        }
+        let remote_root = session.remote_root()?;
+        let mut state = State::load(remote_host, session.local_key(), &remote_root)?;
+
+        if cli.check_conflicts {
+            let conflicts = transfer::conflicts(source, &files, dest, &state)?;
+            if !conflicts.is_empty() {
+                for file in &conflicts {
+                    error!("modified since last sync: {:?}", transfer::join(dest, file));
+                }
+                bail!(
+                    "{} destination files were modified since the last sync, fetch them first or drop --check-conflicts",
+                    conflicts.len()
+                );
+            }
+        }
+
+        let mut source_hashes = HashMap::new();
+        let changed = if cli.checksum {
+            let dest_hashes;
+            (source_hashes, dest_hashes) = checksum::sides(session, &files, &state)?;
+            checksum::changed(&source_hashes, &dest_hashes, &files)
+        } else if cli.ignore_times {
+            files.clone()
+        } else {
+            transfer::changed(source, &files, dest)?
+        };
+        info!(
+            "Copying {} files of {:?} to {:?}, {} unchanged",
+            changed.len(),
+            source,
+            dest,
+            files.len() - changed.len()
+        );
+        let mut outdated = if cli.update {
+            transfer::newer(source, &changed, dest)?
+        } else {
+            changed.clone()
+        };
+        let mut linked = Vec::new();
+        if let Some(link_dest) = &cli.link_dest {
+            if !matches!(session.direction, Direction::Upload) {
+                bail!("--link-dest only supports uploading");
+            }
+            let link_dest =
+                shellexpand::tilde_with_context(link_dest, || session.remote_home.clone());
+            let remote_previous = session.remote_path(dest)?.join(link_dest.as_ref());
+            let previous = session.mounted(&remote_previous);
+            if !previous.is_dir() && !previous.is_file() {
+                bail!("Nothing to link from at {remote_previous:?}");
+            }
+            linked = transfer::link_unchanged(source, &outdated, &previous, dest)?;
+            info!(
+                "Hardlinked {} unchanged files from {remote_previous:?}",
+                linked.len()
+            );
+            let linked: HashSet<&PathBuf> = linked.iter().collect();
+            outdated.retain(|file| !linked.contains(file));
+        }
+        let mut duplicates = Vec::new();
+        if cli.dedup {
+            if !matches!(session.direction, Direction::Upload) {
+                bail!("--dedup only supports uploading");
+            }
+            (outdated, duplicates) = dedup::split(source, outdated, &state)?;
+        }
+        if !cli.no_space_check {
+            space::check(source, &outdated, dest)?;
+        }
+        if cli.auto_tune {
+            // away from the destination, unless the remote has no `/tmp`
+            let tmp = session.mounted(Path::new("/tmp"));
+            let scratch = if tmp.is_dir() {
+                tmp
+            } else {
+                dest.ancestors()
+                    .find(|dir| dir.is_dir())
+                    .unwrap_or(dest)
+                    .to_path_buf()
+            };
+            let parblock = driver_name(cli, config) == Some("parblock");
+            let tuned = tune::probe(&session.direction, source, &outdated, &scratch, parblock)?;
+            if let Some(mut tuned) = tuned {
+                if !parblock {
+                    tuned.block_size = tuning.block_size;
+                }
+                info!(
+                    "Auto-tuned to {} workers and {} byte blocks",
+                    tuned.workers, tuned.block_size
+                );
+                opts = xcp_opts(cli, config, session, tuned)?;
+            }
+        }
+        // holes are recreated on the destination
+        let sparse = match session.direction {
+            _ if outdated.is_empty() || session.windows => HashSet::new(),
+            Direction::Upload => sparse::local(source, &outdated)?,
+            Direction::Download => sparse::remote(remote_host, &session.remote_path(source)?)?,
+        };
+        let copy_opts = CopyOpts {
+            // a rerun picks up interrupted downloads, or any kept partial file,
+            // where they stopped, unless converted
+            resume: (matches!(session.direction, Direction::Download) || cli.partial_dir.is_some())
+                && cli.text_mode.is_none()
+                && !cli.no_resume,
+            no_clobber: cli.no_clobber,
+            interactive: cli.interactive,
+            sparse,
+            uring: cli.local_io == LocalIo::Uring,
+            progress: Some(progress.clone()),
+            // a remote could have replaced a symlink it sent before by a directory
+            confined: matches!(session.direction, Direction::Download),
+            partial_dir: cli.partial_dir.clone(),
+            report: Some(report.clone()),
+            numeric_ids: cli.numeric_ids,
+            no_perms: cli.no_perms || session.windows,
+            no_fsync: cli.no_fsync,
+            // hardlinks of the previous snapshot are not written through
+            unlink: cli.link_dest.is_some(),
+            // only kept permissions are masked, downloads by the local umask
+            // like new files of `scp`
+            perms_umask: cli
+                .perms_umask
+                .or(session.profile.perms_umask)
+                .or(config.perms_umask)
+                .or_else(|| matches!(session.direction, Direction::Download).then(transfer::umask))
+                .filter(|_| !cli.no_perms && !session.windows),
+            // parblock already splits large files over its workers
+            mmap: matches!(session.direction, Direction::Upload)
+                && driver_name(cli, config) != Some("parblock"),
+            text: cli
+                .text_mode
+                .map(|eol| Conversion::new(source, eol))
+                .transpose()?,
+        };
+        let mut total = 0;
+        for file in &outdated {
+            total += walk::stat(&transfer::join(source, file))?.len();
+        }
+        progress.start(outdated.len() as u64, total);
+        if cli.stall_timeout.is_some() || cli.file_timeout.is_some() {
+            let abort = abort.clone();
+            progress.watch(
+                cli.stall_timeout.map(Duration::from_secs),
+                cli.file_timeout.map(Duration::from_secs),
+                move |reason| abort.trigger(reason),
+            );
+        }
+        // sshfs reconnects by itself, the files left are then copied again
+        let mut copied = Vec::new();
+        let mut pending = outdated.clone();
+        let mut retries = 0;
+        if cli.backend == Backend::Rsync && !pending.is_empty() {
+            rsync::copy(session, &pending, &copy_opts)?;
+            for file in &pending {
+                let size = walk::stat(&transfer::join(source, file))?.len();
+                report.done(file, "copied", size);
+            }
+            copied.append(&mut pending);
+        }
+        while !pending.is_empty() {
+            let result =
+                transfer::copy_files(&opts, source, &pending, dest, stats.clone(), &copy_opts);
+            abort.check()?;
+            match result {
+                Ok(done) => {
+                    copied.extend(done);
+                    break;
+                }
+                Err(e) if retries < RETRIES && !cli.interactive && connection_lost(&e) => {
+                    retries += 1;
+                    warn!(
+                        "Connection lost ({e:#}), going on in {}s",
+                        RETRY_DELAY.as_secs()
+                    );
+                    std::thread::sleep(RETRY_DELAY);
+                    let left: HashSet<PathBuf> = transfer::changed(source, &pending, dest)?
+                        .into_iter()
+                        .collect();
+                    copied.extend(pending.iter().filter(|f| !left.contains(*f)).cloned());
+                    pending.retain(|f| left.contains(f));
+                    report.retried(&pending);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        for dir in &empty_dirs {
+            if copy_opts.confined {
+                transfer::check_no_symlink_parents(dest, dir)?;
+            }
+            transfer::mkdirs(&transfer::join(dest, dir))?;
+        }
+        if !duplicates.is_empty() {
+            dedup::copy_remote(remote_host, &session.remote_path(dest)?, &duplicates)?;
+            info!("Copied {} duplicates remotely", duplicates.len());
+            for duplicate in &duplicates {
+                let metadata = walk::stat(&transfer::join(source, &duplicate.file))?;
+                let to = transfer::join(dest, &duplicate.file);
+                transfer::copy_attributes(&metadata, &to, &copy_opts)?;
+                report.done(&duplicate.file, "copied", metadata.len());
+            }
+            copied.extend(duplicates.into_iter().map(|d| d.file));
+        }
+        if !fifos.is_empty() {
+            special::make_fifos(session, &fifos)?;
+        }
+        if cli.verify {
+            let failed = checksum::verify(session, &copied)?;
+            if !failed.is_empty() {
+                warn!(
+                    "{} files failed verification, copying them again",
+                    failed.len()
+                );
+                // start over, a corrupted file is no base to resume from
+                transfer::copy_files(
+                    &opts,
+                    source,
+                    &failed,
+                    dest,
+                    stats.clone(),
+                    &CopyOpts {
+                        unlink: copy_opts.unlink,
+                        no_perms: copy_opts.no_perms,
+                        no_fsync: copy_opts.no_fsync,
+                        ..Default::default()
+                    },
+                )?;
+                let failed = checksum::verify(session, &failed)?;
+                if !failed.is_empty() {
+                    for file in &failed {
+                        error!("corrupted: {:?}", transfer::join(dest, file));
+                    }
+                    bail!("{} files failed verification", failed.len());
+                }
+            }
+        }
+        drop(stats);
+        for file in &linked {
+            report.done(file, "linked", 0);
+        }
+        copied.extend(linked);
+        let copied: HashSet<&PathBuf> = copied.iter().collect();
+        // files left alone by --update, --no-clobber or --interactive are not
+        // in sync
+        let skipped: HashSet<&PathBuf> = changed.iter().filter(|f| !copied.contains(f)).collect();
+        let changed_files: HashSet<&PathBuf> = changed.iter().collect();
+        for file in &files {
+            if !changed_files.contains(file) {
+                report.done(file, "unchanged", 0);
+            } else if skipped.contains(file) {
+                report.done(file, "skipped", 0);
+            }
+        }
+        // a copy failing in the channel must not be recorded as in sync
+        if let Some(gather) = gather.take() {
+            gather.join().unwrap()?;
+        }
+        for file in files.iter().filter(|f| !skipped.contains(f)) {
+            let metadata = walk::stat(&transfer::join(source, file))?;
+            state.record(file, &metadata, source_hashes.remove(file))?;
+        }
+        state.retain(|file| walk::stat(&transfer::join(source, file)).is_ok());
+        state.save()?;
+        // deletions are only known for git working trees
+        if cli.tracked_only && cli.no_delete {
+            for file in git::deleted_files(source)? {
+                info!("skip deleting {:?}", dest.join(file));
+            }
+        } else if cli.delete {
+            for file in git::deleted_files(source)? {
+                match &trash {
+                    Some(trash) => trash::move_to(&dest.join(&file), trash, &file)?,
+                    None => transfer::remove(&dest.join(&file))?,
+                }
+                report.done(&file, "deleted", 0);
+            }
+        }
+        // after deletions, which touch the directories
+        transfer::copy_dir_attributes(source, &files, &empty_dirs, dest, &copy_opts)?;
+        if cli.move_source {
+            let in_sync: Vec<PathBuf> = files
+                .iter()
+                .filter(|f| !skipped.contains(f))
+                .cloned()
+                .collect();
+            let mut failed: HashSet<PathBuf> =
+                checksum::verify(session, &in_sync)?.into_iter().collect();
+            // symlinks have no hash, their targets must match instead
+            for file in &in_sync {
+                let from = transfer::join(source, file);
+                if !walk::stat(&from).is_ok_and(|m| m.is_symlink()) {
+                    continue;
+                }
+                let same = match (from.read_link(), transfer::join(dest, file).read_link()) {
+                    (Ok(source_target), Ok(dest_target)) => source_target == dest_target,
+                    _ => false,
+                };
+                if !same {
+                    failed.insert(file.clone());
+                }
+            }
+            for file in in_sync.iter().filter(|f| !failed.contains(*f)) {
+                transfer::remove(&transfer::join(source, file))?;
+            }
+            // like `mv`, the emptied directories go too, deepest first
+            let mut dirs: Vec<&Path> = in_sync
+                .iter()
+                .flat_map(|f| f.ancestors().skip(1))
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect();
+            dirs.sort_by_key(|dir| (std::cmp::Reverse(dir.components().count()), *dir));
+            dirs.dedup();
+            for dir in dirs {
+                // still holding ignored or differing files
+                let _ = std::fs::remove_dir(source.join(dir));
+            }
+            if !failed.is_empty() {
+                for file in &failed {
+                    error!("not moved: {:?}", transfer::join(source, file));
+                }
+                bail!("{} files differ on the destination", failed.len());
+            }
+        }
+    }
+
+    if let Some(gather) = gather {
+        gather.join().unwrap()?;
+    }
+    progress.finish();
+    Ok(())
+}
+
+/// The xcp options of copying as `cli` and `config` ask, with `tuning`
+fn xcp_opts(
+    cli: &Cli,
+    config: &Config,
+    session: &Session,
+    tuning: Tuning,
+) -> Result<Arc<xcp::options::Opts>> {
+    let driver = match driver_name(cli, config) {
+        None | Some("parfile") => Drivers::ParFile,
+        Some("parblock") => Drivers::ParBlock,
+        Some(driver) => bail!("Unknown driver {driver:?} expect parfile or parblock"),
+    };
+    Ok(Arc::new(xcp::options::Opts {
+        gitignore: true,
+        recursive: true,
+        fsync: !cli.no_fsync,
+        verbose: cli.verbose,
+        workers: tuning.workers,
+        block_size: tuning.block_size,
+        no_clobber: cli.no_clobber,
+        glob: false,
+        no_progress: false,
+        // Windows has no POSIX permissions to keep
+        no_perms: cli.no_perms || session.windows,
+        driver,
+        // the destination is resolved up front by `transfer::target_base`
+        no_target_directory: true,
+        reflink: match cli.reflink {
+            ReflinkMode::Always => Reflink::Always,
+            ReflinkMode::Auto => Reflink::Auto,
+            ReflinkMode::Never => Reflink::Never,
+        },
+        paths: vec![],
+    }))
+}
+
+/// The xcp driver given on the command line, or else in the config file
+fn driver_name<'a>(cli: &'a Cli, config: &'a Config) -> Option<&'a str> {
+    cli.driver.as_deref().or(config.driver.as_deref())
+}
+
+/// Whether `e` comes from the sshfs connection going away
+fn connection_lost(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::raw_os_error)
+            .is_some_and(|errno| matches!(errno, libc::ENOTCONN | libc::ECONNABORTED | libc::EIO))
+    })
+}
@@ -0,0 +1,176 @@
+use crate::transfer;
+use crate::transfer::CopyOpts;
+use anyhow::Context;
+use anyhow::Result;
+use clap::ValueEnum;
+use ignore::gitignore::Gitignore;
+use ignore::gitignore::GitignoreBuilder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use xcp::operations::StatSender;
+use xcp::operations::StatusUpdate;
+
+/// How many leading bytes are looked at for a NUL, like git does, to tell
+/// binary files
+const SNIFF: usize = 8000;
+
+/// Bytes converted at once
+const CHUNK: usize = 64 * 1024;
+
+/// Line endings
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Eol {
+    Lf,
+    Crlf,
+}
+
+/// Line ending conversion of text files, following the `.gitattributes` of
+/// the source
+pub struct Conversion {
+    source: PathBuf,
+    /// for text files without an `eol` attribute
+    eol: Eol,
+    /// the rules of the `.gitattributes` of each source directory, by its
+    /// path relative to the source, read once needed
+    rules: Mutex<HashMap<PathBuf, Arc<Vec<Rule>>>>,
+}
+
+/// A pattern of `.gitattributes`, with its `text` and `eol` settings
+type Rule = (Gitignore, Option<bool>, Option<Eol>);
+
+impl Conversion {
+    /// Convert to `eol`, reading the `.gitattributes` files under `source`
+    pub fn new(source: &Path, eol: Eol) -> Result<Conversion> {
+        Ok(Conversion {
+            source: source.to_path_buf(),
+            eol,
+            rules: Mutex::default(),
+        })
+    }
+
+    /// Write `from` to `to` with converted line endings, unless `file`
+    /// (relative to the source) is binary, reporting the bytes read to `stats`
+    ///
+    /// Returns false, touching nothing, for binary files
+    pub fn copy(
+        &self,
+        file: &Path,
+        from: &Path,
+        to: &Path,
+        stats: &StatSender,
+        copy_opts: &CopyOpts,
+    ) -> Result<bool> {
+        // the last matching line wins, per attribute, and deeper files win
+        // over those of their parents like in git
+        let (mut text, mut eol) = (None, None);
+        let dirs: Vec<&Path> = file.ancestors().skip(1).collect();
+        for dir in dirs.into_iter().rev() {
+            // every ancestor is a prefix
+            let relative = file.strip_prefix(dir).unwrap();
+            for (pattern, rule_text, rule_eol) in self.rules(dir)?.iter() {
+                if pattern
+                    .matched_path_or_any_parents(relative, false)
+                    .is_ignore()
+                {
+                    text = rule_text.or(text);
+                    eol = rule_eol.or(eol);
+                }
+            }
+        }
+        if text == Some(false) || !from.is_file() {
+            return Ok(false);
+        }
+        let mut reader = File::open(from).with_context(|| format!("Fail to read {from:?}"))?;
+        let mut sniffed = Vec::with_capacity(SNIFF);
+        (&mut reader).take(SNIFF as u64).read_to_end(&mut sniffed)?;
+        if text.is_none() && eol.is_none() && sniffed.contains(&0) {
+            return Ok(false);
+        }
+        let crlf = eol.unwrap_or(self.eol) == Eol::Crlf;
+        let mut writer =
+            BufWriter::new(File::create(to).with_context(|| format!("Fail to write {to:?}"))?);
+        let mut reader = sniffed.as_slice().chain(reader);
+        let mut buf = vec![0; CHUNK];
+        // a `\r` held back, dropped when a `\n` follows
+        let mut cr = false;
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).with_context(|| format!("Fail to read {from:?}")),
+            };
+            for &b in &buf[..n] {
+                if std::mem::take(&mut cr) && b != b'\n' {
+                    writer.write_all(b"\r")?;
+                }
+                match b {
+                    b'\r' => cr = true,
+                    b'\n' if crlf => writer.write_all(b"\r\n")?,
+                    _ => writer.write_all(&[b])?,
+                }
+            }
+            stats.send(StatusUpdate::Copied(n as u64))?;
+        }
+        if cr {
+            writer.write_all(b"\r")?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Fail to write {to:?}"))?;
+        transfer::finish_copy(writer.get_ref(), &from.metadata()?, to, copy_opts)?;
+        Ok(true)
+    }
+
+    /// The rules of the `.gitattributes` in `dir`, relative to the source
+    fn rules(&self, dir: &Path) -> Result<Arc<Vec<Rule>>> {
+        if let Some(rules) = self.rules.lock().unwrap().get(dir) {
+            return Ok(rules.clone());
+        }
+        let rules = Arc::new(read_rules(&self.source.join(dir))?);
+        self.rules
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), rules.clone());
+        Ok(rules)
+    }
+}
+
+/// The rules of `dir/.gitattributes`, none without one
+fn read_rules(dir: &Path) -> Result<Vec<Rule>> {
+    let path = dir.join(".gitattributes");
+    let mut rules = Vec::new();
+    if !path.is_file() {
+        return Ok(rules);
+    }
+    let content =
+        std::fs::read_to_string(&path).with_context(|| format!("Fail to read {path:?}"))?;
+    for line in content.lines().map(str::trim) {
+        let mut words = line.split_whitespace();
+        let Some(pattern) = words.next().filter(|p| !p.starts_with('#')) else {
+            continue;
+        };
+        let (mut text, mut eol) = (None, None);
+        for attribute in words {
+            match attribute {
+                "text" => text = Some(true),
+                "-text" | "binary" => text = Some(false),
+                "eol=lf" => eol = Some(Eol::Lf),
+                "eol=crlf" => eol = Some(Eol::Crlf),
+                _ => {}
+            }
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        builder.add_line(None, pattern)?;
+        rules.push((builder.build()?, text, eol));
+    }
+    Ok(rules)
+}
@@ -0,0 +1,826 @@
+use crate::mapped;
+use crate::output;
+use crate::progress::Progress;
+use crate::report::Report;
+use crate::sparse;
+use crate::state::mtime_secs;
+use crate::state::State;
+use crate::text::Conversion;
+use crate::uring;
+use crate::walk;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+use log::warn;
+use std::collections::HashSet;
+use std::fs::File;
+use std::fs::Metadata;
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::fs::lchown;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use xcp::drivers::load_driver;
+use xcp::drivers::CopyDriver;
+use xcp::drivers::Drivers;
+use xcp::errors::XcpError;
+use xcp::operations::StatSender;
+use xcp::operations::StatusUpdate;
+use xcp::options::Opts;
+
+/// Where `source` ends up when copied to `dest`, following `cp -r` semantics:
+/// copying into an existing directory creates `dest/<source name>`
+pub fn target_base(source: &Path, dest: &Path, no_target_directory: bool) -> Result<PathBuf> {
+    if dest.is_dir() && !no_target_directory {
+        let name = source
+            .file_name()
+            .ok_or(XcpError::InvalidSource("Failed to find source file name."))?;
+        Ok(dest.join(name))
+    } else {
+        Ok(dest.to_path_buf())
+    }
+}
+
+/// `root/rel`, where an empty `rel` stands for `root` itself
+pub fn join(root: &Path, rel: &Path) -> PathBuf {
+    if rel.as_os_str().is_empty() {
+        root.to_path_buf()
+    } else {
+        root.join(rel)
+    }
+}
+
+/// Create `dir` and its missing parents, naming the one in the way when an
+/// existing file rather than a directory stops them
+pub fn mkdirs(dir: &Path) -> Result<()> {
+    let Err(e) = std::fs::create_dir_all(dir) else {
+        return Ok(());
+    };
+    if e.kind() == ErrorKind::AlreadyExists || e.kind() == ErrorKind::NotADirectory {
+        // the deepest existing one is where creation stopped
+        if let Some(blocker) = dir.ancestors().find(|x| x.symlink_metadata().is_ok()) {
+            if !blocker.is_dir() {
+                bail!("Fail to create directory {dir:?}: {blocker:?} is not a directory");
+            }
+        }
+    }
+    Err(e).with_context(|| format!("Fail to create directory {dir:?}"))
+}
+
+/// The umask of this process, what new local files would be masked with
+pub fn umask() -> u32 {
+    // reading it without setting it, which would race with other threads
+    if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+        let mask = status
+            .lines()
+            .find_map(|line| line.strip_prefix("Umask:"))
+            .and_then(|mask| u32::from_str_radix(mask.trim(), 8).ok());
+        if let Some(mask) = mask {
+            return mask;
+        }
+    }
+    let mask = unsafe { libc::umask(0o022) };
+    unsafe { libc::umask(mask) };
+    mask as u32
+}
+
+/// How many bytes before the end of a partial file must match the source for
+/// the copy to be resumed
+const RESUME_CHECK: u64 = 64 * 1024;
+
+/// How `copy_files` treats each file
+#[derive(Default)]
+pub struct CopyOpts {
+    /// Continue interrupted copies instead of starting over, see `resume`
+    pub resume: bool,
+    /// Leave existing destination files alone
+    pub no_clobber: bool,
+    /// Ask before overwriting existing destination files
+    pub interactive: bool,
+    /// Files to copy keeping their holes
+    pub sparse: HashSet<PathBuf>,
+    /// Convert line endings of text files
+    pub text: Option<Conversion>,
+    /// Read large sources through a memory map, see `mapped::copy`
+    pub mmap: bool,
+    /// Copy regular files through io_uring
+    pub uring: bool,
+    /// Show which file is being copied
+    pub progress: Option<Progress>,
+    /// Refuse to write through symlinks inside the destination
+    pub confined: bool,
+    /// Copy regular files into this directory next to their target, renamed
+    /// over it once complete, so an interruption leaves no truncated file
+    pub partial_dir: Option<PathBuf>,
+    /// Record how each file went
+    pub report: Option<Report>,
+    /// Keep the uid and gid of the source, as numbers
+    pub numeric_ids: bool,
+    /// Permission bits cleared on copied regular files
+    pub perms_umask: Option<u32>,
+    /// Leave permissions alone, failing to set attributes is only a warning
+    pub no_perms: bool,
+    /// Leave flushing copied files to disk to the kernel
+    pub no_fsync: bool,
+    /// Remove an existing destination file before writing it, as it may be a
+    /// hardlink into an earlier copy, see `link_unchanged`
+    pub unlink: bool,
+}
+
+/// Copy `files` (relative to `source`) to the same relative paths under `dest`
+///
+/// The source mtime is kept, so later runs can tell what changed on either side.
+/// Files are copied by `opts.workers` threads at once, each with its own xcp
+/// driver, but one at a time when asking before overwriting or when parblock
+/// already splits each file over its workers. Returns the files actually
+/// copied
+pub fn copy_files(
+    opts: &Arc<Opts>,
+    source: &Path,
+    files: &[PathBuf],
+    dest: &Path,
+    stats: StatSender,
+    copy_opts: &CopyOpts,
+) -> Result<Vec<PathBuf>> {
+    let workers = if copy_opts.interactive || matches!(opts.driver, Drivers::ParBlock) {
+        1
+    } else {
+        opts.workers.max(1)
+    };
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    // the answer to "all" or "none"
+    let overwrite_all = Mutex::new(None);
+    // removed once no worker may still be writing into them
+    let staging_dirs = Mutex::new(HashSet::new());
+    let copied = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers.min(files.len()))
+            .map(|_| {
+                let stats = stats.clone();
+                let (next, failed, overwrite_all, staging_dirs, copied) =
+                    (&next, &failed, &overwrite_all, &staging_dirs, &copied);
+                scope.spawn(move || -> Result<()> {
+                    let driver = load_driver(opts)?;
+                    while !failed.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(file) = files.get(i) else {
+                            break;
+                        };
+                        let done = copy_file(
+                            driver.as_ref(),
+                            source,
+                            file,
+                            dest,
+                            &stats,
+                            copy_opts,
+                            overwrite_all,
+                            staging_dirs,
+                        );
+                        match done {
+                            Ok(true) => copied.lock().unwrap().push(i),
+                            Ok(false) => {}
+                            Err(e) => {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Result<()>>()
+    })?;
+    // unless other partial files are left in them
+    for staging in staging_dirs.into_inner().unwrap() {
+        let _ = std::fs::remove_dir(staging);
+    }
+    let mut copied = copied.into_inner().unwrap();
+    copied.sort();
+    Ok(copied.into_iter().map(|i| files[i].clone()).collect())
+}
+
+/// Copy `file` of `copy_files`, returning false when it was left alone
+#[allow(clippy::too_many_arguments)]
+fn copy_file(
+    driver: &dyn CopyDriver,
+    source: &Path,
+    file: &Path,
+    dest: &Path,
+    stats: &StatSender,
+    copy_opts: &CopyOpts,
+    overwrite_all: &Mutex<Option<bool>>,
+    staging_dirs: &Mutex<HashSet<PathBuf>>,
+) -> Result<bool> {
+    let from = join(source, file);
+    // the target itself, for the copying code which keeps symlinks
+    let from = if walk::following() && from.is_symlink() {
+        from.canonicalize()
+            .with_context(|| format!("Fail to follow the symlink {from:?}"))?
+    } else {
+        from
+    };
+    let to = join(dest, file);
+    // recreated rather than opened, a dangling one has nothing to read
+    let link = from.is_symlink();
+    if copy_opts.confined {
+        check_no_symlink_parents(dest, file)?;
+    }
+    if let Some(parent) = to.parent() {
+        mkdirs(parent)?;
+    }
+    if copy_opts.no_clobber && to.symlink_metadata().is_ok() {
+        output::event("exists, skipped", &[&to]);
+        return Ok(false);
+    }
+    if copy_opts.interactive && to.symlink_metadata().is_ok() {
+        let mut overwrite_all = overwrite_all.lock().unwrap();
+        let overwrite = match *overwrite_all {
+            Some(overwrite) => overwrite,
+            None => match ask_overwrite(&from, &to)? {
+                Answer::Yes => true,
+                Answer::No => false,
+                Answer::All => *overwrite_all.insert(true),
+                Answer::None => *overwrite_all.insert(false),
+            },
+        };
+        if !overwrite {
+            output::event("kept", &[&to]);
+            return Ok(false);
+        }
+    }
+    if let Some(progress) = &copy_opts.progress {
+        progress.start_file(&to);
+    }
+    if let Some(report) = &copy_opts.report {
+        report.start(file);
+    }
+    // what gets written, the partial file kept from an interrupted run
+    // being the base to resume from
+    let target = match &copy_opts.partial_dir {
+        Some(partial_dir) if !link && from.is_file() => {
+            let staging = to.with_file_name(partial_dir);
+            mkdirs(&staging)?;
+            staging.join(to.file_name().unwrap_or_default())
+        }
+        _ => to.clone(),
+    };
+    if copy_opts.unlink && !link && target == to {
+        match std::fs::remove_file(&to) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Fail to replace {to:?}"));
+            }
+            _ => {}
+        }
+    }
+    let resumed = if copy_opts.resume && !link {
+        resume(&from, &target, stats, copy_opts)?
+    } else {
+        None
+    };
+    // the bytes not reported along the way to `stats`, all of them unless
+    // given
+    let unreported = if link {
+        if let Err(e) = copy_symlink(&from, &target) {
+            warn!("skipped: {e:#}");
+            if let Some(progress) = &copy_opts.progress {
+                progress.finish_file(&to, 0);
+            }
+            if let Some(report) = &copy_opts.report {
+                report.done(file, "skipped", 0);
+            }
+            return Ok(false);
+        }
+        None
+    } else if let Some(kept) = resumed {
+        info!("resumed {to:?} after {kept} bytes");
+        Some(kept)
+    } else if copy_opts.sparse.contains(file) {
+        sparse::copy(&from, &target, stats, copy_opts)?;
+        Some(0)
+    } else if let Some(text) = &copy_opts.text {
+        if !text.copy(file, &from, &target, stats, copy_opts)? {
+            driver.copy_single(&from, &target, stats.clone())?;
+        }
+        Some(0)
+    } else if copy_opts.uring && from.is_file() {
+        uring::copy(&from, &target, stats, copy_opts)?;
+        Some(0)
+    } else if copy_opts.mmap && mapped::copy(&from, &target, stats, copy_opts)? {
+        info!("copied {to:?} from a memory map");
+        Some(0)
+    } else {
+        driver.copy_single(&from, &target, stats.clone())?;
+        Some(0)
+    };
+
+    let metadata = walk::stat(&from)?;
+    if let Some(progress) = &copy_opts.progress {
+        progress.finish_file(&to, unreported.unwrap_or(metadata.len()));
+    }
+    if metadata.is_file() {
+        let set = File::options()
+            .write(true)
+            .open(&target)?
+            .set_modified(metadata.modified()?)
+            .with_context(|| format!("Fail to set mtime of {to:?}"));
+        // sftp-only chroots and object storage gateways may refuse setstat
+        match set {
+            Err(e) if copy_opts.no_perms => warn!("{e:#}"),
+            set => set?,
+        }
+    }
+    if let (Some(mask), true) = (copy_opts.perms_umask, metadata.is_file()) {
+        let mode = metadata.permissions().mode() & 0o7777 & !mask;
+        std::fs::set_permissions(&target, Permissions::from_mode(mode))
+            .with_context(|| format!("Fail to set permissions of {to:?}"))?;
+    }
+    // sshfs passes the raw ids through, the remote side needs to be root
+    if copy_opts.numeric_ids {
+        lchown(&target, Some(metadata.uid()), Some(metadata.gid()))
+            .with_context(|| format!("Fail to set the owner of {to:?}"))?;
+    }
+    if target != to {
+        std::fs::rename(&target, &to)
+            .with_context(|| format!("Fail to rename {target:?} to {to:?}"))?;
+        if let Some(staging) = target.parent() {
+            staging_dirs.lock().unwrap().insert(staging.to_path_buf());
+        }
+    }
+    if let Some(report) = &copy_opts.report {
+        report.done(file, "copied", metadata.len());
+    }
+    // humans follow the progress bar instead
+    if output::porcelain() {
+        output::event("copied", &[&to]);
+    }
+    Ok(true)
+}
+
+/// Give the directories of `files`, and `empty_dirs`, under `dest` the mtime,
+/// and unless `no_perms` the permissions, of those under `source`, deepest
+/// first so the parents are done last
+///
+/// Failures are only warnings with `copy_opts.no_perms`
+pub fn copy_dir_attributes(
+    source: &Path,
+    files: &[PathBuf],
+    empty_dirs: &[PathBuf],
+    dest: &Path,
+    copy_opts: &CopyOpts,
+) -> Result<()> {
+    if !source.is_dir() {
+        return Ok(());
+    }
+    let mut dirs: Vec<&Path> = files
+        .iter()
+        .flat_map(|f| f.ancestors().skip(1))
+        .chain(empty_dirs.iter().flat_map(|dir| dir.ancestors()))
+        .collect();
+    dirs.sort_by_key(|dir| (std::cmp::Reverse(dir.components().count()), *dir));
+    dirs.dedup();
+    for dir in dirs {
+        let from = join(source, dir);
+        let to = join(dest, dir);
+        let copied = (|| -> Result<()> {
+            let metadata = walk::stat(&from)?;
+            if !copy_opts.no_perms {
+                std::fs::set_permissions(&to, metadata.permissions())?;
+            }
+            if copy_opts.numeric_ids {
+                lchown(&to, Some(metadata.uid()), Some(metadata.gid()))?;
+            }
+            File::open(&to)?.set_modified(metadata.modified()?)?;
+            Ok(())
+        })()
+        .with_context(|| format!("Fail to set the attributes of {to:?}"));
+        match copied {
+            Err(e) if copy_opts.no_perms => warn!("{e:#}"),
+            copied => copied?,
+        }
+    }
+    Ok(())
+}
+
+/// Give `to`, a copy made outside of `copy_file` of a file of `metadata`, the
+/// mtime of its source and, unless `no_perms`, its permissions less
+/// `perms_umask`, and with `numeric_ids` its owner
+///
+/// Failures are only warnings with `copy_opts.no_perms`
+pub fn copy_attributes(metadata: &Metadata, to: &Path, copy_opts: &CopyOpts) -> Result<()> {
+    let copied = (|| -> Result<()> {
+        if !copy_opts.no_perms {
+            let mask = copy_opts.perms_umask.unwrap_or(0);
+            let mode = metadata.permissions().mode() & 0o7777 & !mask;
+            std::fs::set_permissions(to, Permissions::from_mode(mode))?;
+        }
+        if copy_opts.numeric_ids {
+            lchown(to, Some(metadata.uid()), Some(metadata.gid()))?;
+        }
+        File::options()
+            .write(true)
+            .open(to)?
+            .set_modified(metadata.modified()?)?;
+        Ok(())
+    })()
+    .with_context(|| format!("Fail to set the attributes of {to:?}"));
+    match copied {
+        Err(e) if copy_opts.no_perms => warn!("{e:#}"),
+        copied => copied?,
+    }
+    Ok(())
+}
+
+/// Complete `writer`, the copy at `to` of a file of `metadata` written by one
+/// of our own copies rather than by the xcp driver: flush it to disk and give
+/// it the permissions of its source, unless told not to
+pub fn finish_copy(
+    writer: &File,
+    metadata: &Metadata,
+    to: &Path,
+    copy_opts: &CopyOpts,
+) -> Result<()> {
+    if !copy_opts.no_fsync {
+        writer
+            .sync_all()
+            .with_context(|| format!("Fail to flush {to:?}"))?;
+    }
+    if !copy_opts.no_perms {
+        std::fs::set_permissions(to, metadata.permissions())
+            .with_context(|| format!("Fail to set permissions of {to:?}"))?;
+    }
+    Ok(())
+}
+
+/// Make `to` a symlink to where the symlink `from` points, replacing a file
+/// there
+fn copy_symlink(from: &Path, to: &Path) -> Result<()> {
+    let target =
+        std::fs::read_link(from).with_context(|| format!("Fail to read the symlink {from:?}"))?;
+    match std::fs::remove_file(to) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Fail to replace {to:?}"));
+        }
+        _ => {}
+    }
+    std::os::unix::fs::symlink(&target, to)
+        .with_context(|| format!("Fail to create the symlink {to:?} -> {target:?}"))
+}
+
+/// Fail when a directory between `dest` and `dest/file` is a symlink, left by
+/// an earlier copy, through which `file` would be written outside `dest`
+pub fn check_no_symlink_parents(dest: &Path, file: &Path) -> Result<()> {
+    for parent in file.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() {
+            break;
+        }
+        let path = dest.join(parent);
+        if path.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+            bail!(
+                "Refuse to write {:?} through the symlink {path:?}",
+                dest.join(file)
+            );
+        }
+    }
+    Ok(())
+}
+
+enum Answer {
+    Yes,
+    No,
+    All,
+    None,
+}
+
+/// Ask on the terminal whether to overwrite `to` with `from`
+fn ask_overwrite(from: &Path, to: &Path) -> Result<Answer> {
+    loop {
+        eprint!("overwrite {to:?}? [y]es, [n]o, [a]ll, [N]one, [d]iff: ");
+        std::io::stderr().flush()?;
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            // no terminal to answer, keep the file
+            return Ok(Answer::None);
+        }
+        match line.trim() {
+            "y" | "yes" => return Ok(Answer::Yes),
+            "n" | "no" => return Ok(Answer::No),
+            "a" | "all" => return Ok(Answer::All),
+            "N" | "none" => return Ok(Answer::None),
+            "d" | "diff" => {
+                Command::new("diff")
+                    .arg("-u")
+                    .arg(to)
+                    .arg(from)
+                    .status()
+                    .context("Fail to execute `diff`")?;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Finish an interrupted copy of `from` into the shorter `to`, appending the
+/// missing bytes and reporting them to `stats`, when `to` was written since
+/// `from` last changed and its last bytes match `from` at the same offset
+///
+/// Returns how many bytes of `to` were kept, or `None`, touching nothing, when
+/// `to` has to be copied from scratch
+pub fn resume(
+    from: &Path,
+    to: &Path,
+    stats: &StatSender,
+    copy_opts: &CopyOpts,
+) -> Result<Option<u64>> {
+    let (Ok(source), Ok(partial)) = (walk::stat(from), to.symlink_metadata()) else {
+        return Ok(None);
+    };
+    let len = partial.len();
+    if !source.is_file() || !partial.is_file() || len == 0 || len >= source.len() {
+        return Ok(None);
+    }
+    // a partial copy of an older version of the source
+    if partial.modified()? < source.modified()? {
+        return Ok(None);
+    }
+
+    let check = len.min(RESUME_CHECK);
+    let mut expected = vec![0; check as usize];
+    let mut found = vec![0; check as usize];
+    let mut reader = File::open(from)?;
+    reader.seek(SeekFrom::Start(len - check))?;
+    reader.read_exact(&mut expected)?;
+    let mut writer = File::options().read(true).write(true).open(to)?;
+    writer.seek(SeekFrom::Start(len - check))?;
+    writer.read_exact(&mut found)?;
+    if expected != found {
+        return Ok(None);
+    }
+
+    // both are now positioned at `len`
+    copy_reporting(&mut reader, &mut writer, stats)
+        .with_context(|| format!("Fail to resume copying {from:?} to {to:?}"))?;
+    finish_copy(&writer, &source, to, copy_opts)?;
+    Ok(Some(len))
+}
+
+/// How many bytes `copy_reporting` reads at once
+const CHUNK: usize = 1024 * 1024;
+
+/// `std::io::copy`, reporting each chunk written to `stats` like xcp does
+pub fn copy_reporting(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    stats: &StatSender,
+) -> Result<u64> {
+    let mut buf = vec![0; CHUNK];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+        stats.send(StatusUpdate::Copied(n as u64))?;
+    }
+}
+
+/// `files` whose destination differs from the source in size or mtime, like
+/// rsync's quick check; the others are left alone
+pub fn changed(source: &Path, files: &[PathBuf], dest: &Path) -> Result<Vec<PathBuf>> {
+    // a single walk of the destination instead of a round trip per file
+    let existing = walk::metadata(dest)?;
+    let mut changed = Vec::new();
+    for file in files {
+        let unchanged = match existing.get(file) {
+            Some(to) if to.is_file() => {
+                let from = walk::stat(&join(source, file))?;
+                from.len() == to.len() && mtime_secs(&from)? == mtime_secs(to)?
+            }
+            // symlinks have no mtime of their own kept, but their target
+            Some(to) if to.is_symlink() && !walk::following() => matches!(
+                (
+                    std::fs::read_link(join(source, file)),
+                    std::fs::read_link(join(dest, file))
+                ),
+                (Ok(from), Ok(to)) if from == to
+            ),
+            _ => false,
+        };
+        if !unchanged {
+            changed.push(file.clone());
+        }
+    }
+    Ok(changed)
+}
+
+/// Hardlink those of `files` unchanged since `previous`, an earlier copy of
+/// `source`, from there into `dest` instead of copying them, like rsync's
+/// `--link-dest`
+///
+/// Returns the linked files
+pub fn link_unchanged(
+    source: &Path,
+    files: &[PathBuf],
+    previous: &Path,
+    dest: &Path,
+) -> Result<Vec<PathBuf>> {
+    let changed: HashSet<PathBuf> = changed(source, files, previous)?.into_iter().collect();
+    let mut linked = Vec::new();
+    for file in files.iter().filter(|f| !changed.contains(*f)) {
+        let from = join(previous, file);
+        let to = join(dest, file);
+        if let Some(parent) = to.parent() {
+            mkdirs(parent)?;
+        }
+        match std::fs::remove_file(&to) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Fail to replace {to:?}"));
+            }
+            _ => {}
+        }
+        std::fs::hard_link(&from, &to)
+            .with_context(|| format!("Fail to hardlink {from:?} to {to:?}"))?;
+        linked.push(file.clone());
+    }
+    Ok(linked)
+}
+
+/// `files` whose destination is missing or older than the source, like
+/// `cp --update`
+pub fn newer(source: &Path, files: &[PathBuf], dest: &Path) -> Result<Vec<PathBuf>> {
+    let existing = walk::metadata(dest)?;
+    let mut newer = Vec::new();
+    for file in files {
+        let is_newer = match existing.get(file) {
+            Some(to) => {
+                let from = walk::stat(&join(source, file))?;
+                mtime_secs(&from)? > mtime_secs(to)?
+            }
+            None => true,
+        };
+        if is_newer {
+            newer.push(file.clone());
+        } else {
+            info!("destination is as new, skipped: {:?}", join(dest, file));
+        }
+    }
+    Ok(newer)
+}
+
+/// `files` whose destination was modified since the last sync, so overwriting
+/// it would lose that edit
+///
+/// Without a record of the last sync, a destination newer than its source is
+/// taken as modified: copies keep the source mtime
+pub fn conflicts(
+    source: &Path,
+    files: &[PathBuf],
+    dest: &Path,
+    state: &State,
+) -> Result<Vec<PathBuf>> {
+    let mut conflicts = Vec::new();
+    for file in files {
+        let Ok(to) = join(dest, file).symlink_metadata() else {
+            continue;
+        };
+        if !to.is_file() {
+            continue;
+        }
+        let modified = match state.get(file) {
+            Some(record) => !record.matches(&to)?,
+            None => mtime_secs(&to)? > mtime_secs(&walk::stat(&join(source, file))?)?,
+        };
+        if modified {
+            conflicts.push(file.clone());
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Delete `path` (recursively for a directory) through the mount and report it
+///
+/// A missing `path` is not an error
+pub fn remove(path: &Path) -> Result<()> {
+    let metadata = match path.symlink_metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Fail to stat {path:?}")),
+    };
+    if metadata.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .with_context(|| format!("Fail to delete {path:?}"))?;
+    output::event("deleted", &[path]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    fn write(path: &Path, content: &str, mtime: SystemTime) {
+        std::fs::write(path, content).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    #[test]
+    fn mkdirs_names_the_file_in_the_way() {
+        let dir = tempfile::tempdir().unwrap();
+        mkdirs(&dir.path().join("a/b")).unwrap();
+        assert!(dir.path().join("a/b").is_dir());
+
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let e = mkdirs(&dir.path().join("file/sub")).unwrap_err();
+        assert!(format!("{e:#}").contains("is not a directory"), "{e:#}");
+    }
+
+    #[test]
+    fn changed_compares_size_and_mtime() {
+        let (source, dest) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (source, dest) = (source.path(), dest.path());
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        write(&source.join("same"), "one", mtime);
+        write(&dest.join("same"), "one", mtime);
+        write(&source.join("resized"), "two", mtime);
+        write(&dest.join("resized"), "2", mtime);
+        write(&source.join("touched"), "three", mtime);
+        write(
+            &dest.join("touched"),
+            "three",
+            mtime + Duration::from_secs(60),
+        );
+        write(&source.join("missing"), "four", mtime);
+        symlink("target", source.join("link")).unwrap();
+        symlink("target", dest.join("link")).unwrap();
+        symlink("target", source.join("relinked")).unwrap();
+        symlink("elsewhere", dest.join("relinked")).unwrap();
+
+        let files: Vec<PathBuf> = ["link", "missing", "relinked", "resized", "same", "touched"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let expected: Vec<PathBuf> = ["missing", "relinked", "resized", "touched"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(changed(source, &files, dest).unwrap(), expected);
+    }
+
+    #[test]
+    fn copy_symlink_recreates_dangling_links_over_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        symlink("../missing/target", &from).unwrap();
+        std::fs::write(&to, "a regular file").unwrap();
+
+        copy_symlink(&from, &to).unwrap();
+        assert_eq!(
+            std::fs::read_link(&to).unwrap(),
+            Path::new("../missing/target")
+        );
+    }
+
+    #[test]
+    fn refuses_writing_through_symlinked_parents() {
+        let (dest, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let dest = dest.path();
+        std::fs::create_dir(dest.join("dir")).unwrap();
+        symlink(outside.path(), dest.join("link")).unwrap();
+
+        check_no_symlink_parents(dest, Path::new("dir/file")).unwrap();
+        check_no_symlink_parents(dest, Path::new("not/yet/there")).unwrap();
+        assert!(check_no_symlink_parents(dest, Path::new("link/file")).is_err());
+        assert!(check_no_symlink_parents(dest, Path::new("link/deeper/file")).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::output;
+use crate::remote;
+use crate::transfer::mkdirs;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Where deleted remote files are moved to, relative to the remote home
+const TRASH_DIR: &str = ".smartscp-trash";
+
+/// The trash directory of this run, `<home>/.smartscp-trash/<unix timestamp>`
+pub fn run_dir(home: &Path) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    home.join(TRASH_DIR).join(now.to_string())
+}
+
+/// Move `path` to `trash/rel` instead of deleting it
+///
+/// A missing `path` is not an error
+pub fn move_to(path: &Path, trash: &Path, rel: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Fail to stat {path:?}")),
+    }
+    let to = trash.join(rel);
+    if let Some(parent) = to.parent() {
+        mkdirs(parent)?;
+    }
+    std::fs::rename(path, &to).with_context(|| format!("Fail to move {path:?} to trash"))?;
+    output::event("trashed", &[path, &to]);
+    Ok(())
+}
+
+/// Delete the trash of runs older than `days` days on `remote_host`
+pub fn purge(remote_host: &str, days: u64) -> Result<()> {
+    let mtime = format!("+{days}");
+    // ssh runs commands in the remote home, where the trash lives
+    let command = remote::and([
+        remote::shell(["test", "-d", TRASH_DIR]),
+        remote::shell([
+            "find",
+            TRASH_DIR,
+            "-mindepth",
+            "1",
+            "-maxdepth",
+            "1",
+            "-mtime",
+            mtime.as_str(),
+            "-print",
+            "-exec",
+            "rm",
+            "-rf",
+            "{}",
+            "+",
+        ]),
+    ]);
+    let out = remote::run(remote_host, &command, None)?;
+    // `test` fails silently when there is no trash at all
+    if !out.status.success() && !out.stderr.is_empty() {
+        bail!("fail to purge remote trash: {}", remote::stderr(&out));
+    }
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        output::field("purged", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use crate::transfer::join;
+use crate::walk;
+use crate::Direction;
+use anyhow::Result;
+use indicatif::HumanBytes;
+use log::info;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Bytes moved by each probe
+const PROBE: u64 = 4 * 1024 * 1024;
+
+/// Transfers smaller than this aren't worth probing
+const THRESHOLD: u64 = 1024 * 1024 * 1024;
+
+const WORKERS: [usize; 4] = [1, 2, 4, 8];
+
+const BLOCK_SIZES: [u64; 4] = [128 * 1024, 256 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+
+/// The copy threads and block size to use
+#[derive(Clone, Copy, Debug)]
+pub struct Tuning {
+    pub workers: usize,
+    pub block_size: u64,
+}
+
+/// The fastest `Tuning` for copying `files` from `source`, found by timing a
+/// few small transfers to (upload) or from (download) the remote side
+///
+/// Uploads are timed writing into a temporary directory under `scratch`, a
+/// remote directory outside of the destination. The block size is only tuned
+/// with `block_sizes`, it matters to the parblock driver alone. Returns None
+/// for transfers too small to be worth it
+pub fn probe(
+    direction: &Direction,
+    source: &Path,
+    files: &[PathBuf],
+    scratch: &Path,
+    block_sizes: bool,
+) -> Result<Option<Tuning>> {
+    let mut total = 0;
+    let mut largest = (0, PathBuf::new());
+    for file in files {
+        let path = join(source, file);
+        let len = walk::stat(&path)?.len();
+        total += len;
+        if len > largest.0 {
+            largest = (len, path);
+        }
+    }
+    if total < THRESHOLD {
+        return Ok(None);
+    }
+    let block_sizes: &[u64] = if block_sizes { &BLOCK_SIZES } else { &[] };
+    // each probe reads a region of its own, not cached by an earlier one
+    let probes = (WORKERS.len() + block_sizes.len()) as u64;
+    if matches!(direction, Direction::Download) && largest.0 < probes * PROBE {
+        return Ok(None);
+    }
+    let scratch = match direction {
+        Direction::Upload => Some(
+            tempfile::Builder::new()
+                .prefix(".smartscp-probe")
+                .tempdir_in(scratch)?,
+        ),
+        Direction::Download => None,
+    };
+    let mut offset = 0;
+    let mut time = |tuning: Tuning| -> Result<Duration> {
+        let elapsed = match &scratch {
+            Some(dir) => write_probe(dir.path(), tuning)?,
+            None => {
+                offset += PROBE;
+                read_probe(&largest.1, offset - PROBE, tuning)?
+            }
+        };
+        info!(
+            "probe: {} workers, {} blocks: {}/s",
+            tuning.workers,
+            HumanBytes(tuning.block_size),
+            HumanBytes((PROBE as f64 / elapsed.as_secs_f64().max(1e-6)) as u64)
+        );
+        Ok(elapsed)
+    };
+
+    // the best thread count first, then the best block size for it
+    let mut best = Tuning {
+        workers: WORKERS[0],
+        block_size: 1024 * 1024,
+    };
+    let mut best_time = Duration::MAX;
+    for workers in WORKERS {
+        let tuning = Tuning { workers, ..best };
+        let elapsed = time(tuning)?;
+        if elapsed < best_time {
+            (best, best_time) = (tuning, elapsed);
+        }
+    }
+    for &block_size in block_sizes {
+        let tuning = Tuning { block_size, ..best };
+        let elapsed = time(tuning)?;
+        if elapsed < best_time {
+            (best, best_time) = (tuning, elapsed);
+        }
+    }
+    Ok(Some(best))
+}
+
+/// How long writing `PROBE` bytes of temporary files into `dir` takes with
+/// `tuning`
+fn write_probe(dir: &Path, tuning: Tuning) -> Result<Duration> {
+    let share = PROBE / tuning.workers as u64;
+    let mut probes = Vec::new();
+    for _ in 0..tuning.workers {
+        probes.push(
+            tempfile::Builder::new()
+                .prefix(".smartscp-probe")
+                .tempfile_in(dir)?,
+        );
+    }
+    // not all zero, in case ssh compresses
+    let block: Vec<u8> = (0..tuning.block_size)
+        .map(|i| (i * 7 + i / 251) as u8)
+        .collect();
+    let start = Instant::now();
+    std::thread::scope(|scope| -> Result<()> {
+        let handles: Vec<_> = probes
+            .iter()
+            .map(|probe| {
+                let block = &block;
+                scope.spawn(move || -> Result<()> {
+                    let mut file = probe.as_file();
+                    let mut written = 0;
+                    while written < share {
+                        let n = (share - written).min(block.len() as u64) as usize;
+                        file.write_all(&block[..n])?;
+                        written += n as u64;
+                    }
+                    file.sync_all()?;
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        Ok(())
+    })?;
+    Ok(start.elapsed())
+}
+
+/// How long reading `PROBE` bytes of `path` from `offset` takes with `tuning`
+fn read_probe(path: &Path, offset: u64, tuning: Tuning) -> Result<Duration> {
+    let share = PROBE / tuning.workers as u64;
+    let start = Instant::now();
+    std::thread::scope(|scope| -> Result<()> {
+        let handles: Vec<_> = (0..tuning.workers as u64)
+            .map(|i| {
+                scope.spawn(move || -> Result<()> {
+                    let mut file = File::open(path)?;
+                    file.seek(SeekFrom::Start(offset + i * share))?;
+                    let mut block = vec![0; tuning.block_size as usize];
+                    let mut read = 0;
+                    while read < share {
+                        let n = (share - read).min(block.len() as u64) as usize;
+                        file.read_exact(&mut block[..n])?;
+                        read += n as u64;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        Ok(())
+    })?;
+    Ok(start.elapsed())
+}
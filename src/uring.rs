@@ -0,0 +1,88 @@
+use crate::transfer;
+use crate::transfer::CopyOpts;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use io_uring::opcode;
+use io_uring::squeue;
+use io_uring::types::Fd;
+use io_uring::IoUring;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use xcp::operations::StatSender;
+use xcp::operations::StatusUpdate;
+
+/// Reads (then writes) submitted at once
+const QUEUE: usize = 8;
+
+/// Bytes of each read and write
+const CHUNK: usize = 1024 * 1024;
+
+/// Copy `from` to `to` through io_uring, `QUEUE` chunks per submission, so a
+/// file costs a few syscalls per `QUEUE` chunks instead of two per chunk,
+/// reporting them to `stats` once written
+pub fn copy(from: &Path, to: &Path, stats: &StatSender, copy_opts: &CopyOpts) -> Result<()> {
+    let reader = File::open(from).with_context(|| format!("Fail to open {from:?}"))?;
+    let len = reader.metadata()?.len();
+    let writer = File::create(to).with_context(|| format!("Fail to create {to:?}"))?;
+    let mut ring = IoUring::new(QUEUE as u32)?;
+    let mut buffers = vec![vec![0u8; CHUNK]; QUEUE];
+    let mut offset = 0;
+    while offset < len {
+        let chunks: Vec<(u64, usize)> = (0..QUEUE as u64)
+            .map(|i| offset + i * CHUNK as u64)
+            .take_while(|at| *at < len)
+            .map(|at| (at, (len - at).min(CHUNK as u64) as usize))
+            .collect();
+        let reads = buffers.iter_mut().zip(&chunks).map(|(buffer, (at, size))| {
+            opcode::Read::new(Fd(reader.as_raw_fd()), buffer.as_mut_ptr(), *size as u32)
+                .offset(*at)
+                .build()
+        });
+        let read =
+            run(&mut ring, reads.collect()).with_context(|| format!("Fail to read {from:?}"))?;
+        if read.iter().zip(&chunks).any(|(n, (_, size))| n != size) {
+            bail!("{from:?} changed while copying it");
+        }
+        let writes = buffers.iter().zip(&chunks).map(|(buffer, (at, size))| {
+            opcode::Write::new(Fd(writer.as_raw_fd()), buffer.as_ptr(), *size as u32)
+                .offset(*at)
+                .build()
+        });
+        let written =
+            run(&mut ring, writes.collect()).with_context(|| format!("Fail to write {to:?}"))?;
+        if written.iter().zip(&chunks).any(|(n, (_, size))| n != size) {
+            bail!("Short write to {to:?}");
+        }
+        let copied = chunks.iter().map(|(_, size)| *size as u64).sum::<u64>();
+        stats.send(StatusUpdate::Copied(copied))?;
+        offset += copied;
+    }
+    transfer::finish_copy(&writer, &reader.metadata()?, to, copy_opts)
+}
+
+/// Submit `entries` at once and wait for all of them, returning their results
+/// in order
+fn run(ring: &mut IoUring, entries: Vec<squeue::Entry>) -> Result<Vec<usize>> {
+    let count = entries.len();
+    for (i, entry) in entries.into_iter().enumerate() {
+        // the buffers outlive the wait below
+        if unsafe { ring.submission().push(&entry.user_data(i as u64)) }.is_err() {
+            bail!("io_uring submission queue is full");
+        }
+    }
+    let mut results = vec![0; count];
+    let mut done = 0;
+    while done < count {
+        ring.submit_and_wait(count - done)?;
+        for cqe in ring.completion() {
+            if cqe.result() < 0 {
+                return Err(std::io::Error::from_raw_os_error(-cqe.result()).into());
+            }
+            results[cqe.user_data() as usize] = cqe.result() as usize;
+            done += 1;
+        }
+    }
+    Ok(results)
+}
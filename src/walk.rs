@@ -0,0 +1,485 @@
+use crate::output;
+use crate::transfer::join;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use globset::GlobBuilder;
+use ignore::gitignore::GitignoreBuilder;
+use ignore::DirEntry;
+use ignore::WalkBuilder;
+use ignore::WalkState;
+use log::warn;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Walk and copy the targets of symlinks instead of the symlinks, like `scp -L`
+static FOLLOW: AtomicBool = AtomicBool::new(false);
+
+pub fn follow_symlinks(follow: bool) {
+    FOLLOW.store(follow, Ordering::Relaxed);
+}
+
+pub fn following() -> bool {
+    FOLLOW.load(Ordering::Relaxed)
+}
+
+/// Honor `.gitignore` files outside git repositories too
+static WITHOUT_GIT: AtomicBool = AtomicBool::new(false);
+
+/// Walking a remote tree, whose `.git` may be missing, like on a host deployed
+/// by copying a checkout, or unreadable without git installed there
+pub fn gitignore_without_git(on: bool) {
+    WITHOUT_GIT.store(on, Ordering::Relaxed);
+}
+
+/// Directories never walked into, like the sshfs mount when it lies under the
+/// local path
+static SKIPPED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+pub fn skip(dir: &Path) {
+    SKIPPED.lock().unwrap().push(dir.to_owned());
+}
+
+fn skipped(entry: &DirEntry) -> bool {
+    entry.depth() > 0
+        && SKIPPED
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|dir| entry.path() == dir)
+}
+
+/// The metadata of a source `path`, of the target of a symlink when following
+/// them
+pub fn stat(path: &Path) -> std::io::Result<Metadata> {
+    if following() {
+        path.metadata()
+    } else {
+        path.symlink_metadata()
+    }
+}
+
+/// Directories listed at once by `files`, each listing being a round trip
+/// over sshfs
+const WALK_THREADS: usize = 8;
+
+/// Files under `source`, relative to it, skipping git-ignored ones as xcp does
+///
+/// A single file yields one empty path, standing for `source` itself. When
+/// following symlinks, loops and dangling ones are skipped. Directories are
+/// listed in parallel from a shared queue, however deep the tree, and the
+/// files come out sorted, all of them before any is copied: the bounded pool
+/// of `transfer::copy_files` only starts on the complete list
+pub fn files(source: &Path) -> Result<Vec<PathBuf>> {
+    Ok(tree(source)?.0)
+}
+
+/// The `files` under `source`, and its directories holding nothing, which
+/// copying the files alone would not create
+pub fn tree(source: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    if !source.is_dir() {
+        return Ok((vec![PathBuf::new()], Vec::new()));
+    }
+    let (files, dirs, failure) = (
+        Mutex::new(Vec::new()),
+        Mutex::new(Vec::new()),
+        Mutex::new(None),
+    );
+    let (found, found_dirs, failed) = (&files, &dirs, &failure);
+    WalkBuilder::new(source)
+        .hidden(false)
+        .git_ignore(true)
+        .require_git(!WITHOUT_GIT.load(Ordering::Relaxed))
+        .follow_links(following())
+        .filter_entry(|entry| !skipped(entry))
+        .threads(WALK_THREADS)
+        .build_parallel()
+        .run(move || {
+            Box::new(move |entry| {
+                let entry = match entry {
+                    Err(e) if following() && skippable(&e) => {
+                        warn!("skipped: {e}");
+                        return WalkState::Continue;
+                    }
+                    Err(e) => {
+                        failed.lock().unwrap().get_or_insert(e);
+                        return WalkState::Quit;
+                    }
+                    Ok(entry) => entry,
+                };
+                // every entry is below the root it was walked from
+                let file = entry.path().strip_prefix(source).unwrap().to_path_buf();
+                if entry.file_type().is_some_and(|t| t.is_dir()) {
+                    if entry.depth() > 0 {
+                        found_dirs.lock().unwrap().push(file);
+                    }
+                    return WalkState::Continue;
+                }
+                found.lock().unwrap().push(file);
+                WalkState::Continue
+            })
+        });
+    if let Some(e) = failure.into_inner().unwrap() {
+        return Err(e.into());
+    }
+    let mut files = files.into_inner().unwrap();
+    files.sort();
+    let dirs = dirs.into_inner().unwrap();
+    // which hold something, a file or another directory
+    let parents: HashSet<&Path> = files
+        .iter()
+        .chain(&dirs)
+        .filter_map(|entry| entry.parent())
+        .collect();
+    let mut empty: Vec<PathBuf> = dirs
+        .iter()
+        .filter(|dir| !parents.contains(dir.as_path()))
+        .cloned()
+        .collect();
+    empty.sort();
+    Ok((files, empty))
+}
+
+/// Everything but directories under `source`, relative to it and sorted,
+/// ignore files and hidden names notwithstanding
+pub fn all(source: &Path) -> Result<Vec<PathBuf>> {
+    if !source.is_dir() {
+        return Ok(vec![PathBuf::new()]);
+    }
+    let mut files = Vec::new();
+    for entry in WalkBuilder::new(source)
+        .standard_filters(false)
+        .filter_entry(|entry| !skipped(entry))
+        .build()
+    {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|t| t.is_dir()) {
+            files.push(entry.path().strip_prefix(source)?.to_path_buf());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A symlink loop or a dangling symlink met while following them
+fn skippable(e: &ignore::Error) -> bool {
+    match e {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::Io(e) => e.kind() == std::io::ErrorKind::NotFound,
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => {
+            skippable(err)
+        }
+        _ => false,
+    }
+}
+
+/// The files named in `list`, one path relative to `source` per line, or per
+/// NUL with `nul`, read from stdin for `-`; nothing is walked
+///
+/// Missing files are skipped, like the deleted ones of `git diff --name-only`
+pub fn listed(source: &Path, list: &Path, nul: bool) -> Result<Vec<PathBuf>> {
+    if !source.is_dir() {
+        bail!("--files-from needs a directory to list files of, got {source:?}");
+    }
+    let bytes = if list == Path::new("-") {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        bytes
+    } else {
+        std::fs::read(list).with_context(|| format!("Fail to read {list:?}"))?
+    };
+    let mut files = Vec::new();
+    let separator = if nul { b'\0' } else { b'\n' };
+    for line in bytes
+        .split(|&b| b == separator)
+        .filter(|line| !line.is_empty())
+    {
+        let file = Path::new(OsStr::from_bytes(line));
+        let file = file.strip_prefix(".").unwrap_or(file);
+        // an absolute entry would be copied onto itself, `..` out of the tree
+        if !file
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("Refuse to copy {file:?} of {list:?}, it leads outside {source:?}");
+        }
+        let path = join(source, file);
+        match stat(&path) {
+            Ok(metadata) if metadata.is_dir() => {
+                bail!("{path:?} is a directory, list the files inside it instead")
+            }
+            Ok(_) => files.push(file.to_path_buf()),
+            Err(_) => warn!("not found, skipped: {path:?}"),
+        }
+    }
+    Ok(files)
+}
+
+/// Those of `files` matching `pattern`, or inside a directory matching it
+///
+/// Like in a shell, `*` doesn't match across `/`
+pub fn matching(files: Vec<PathBuf>, pattern: &str) -> Result<Vec<PathBuf>> {
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+    Ok(files
+        .into_iter()
+        .filter(|file| {
+            file.ancestors()
+                .any(|x| !x.as_os_str().is_empty() && matcher.is_match(x))
+        })
+        .collect())
+}
+
+/// Those of `files` (relative to `source`) not matching any of `excludes`,
+/// gitignore-style patterns
+pub fn excluding<S: AsRef<str>>(
+    source: &Path,
+    files: Vec<PathBuf>,
+    excludes: &[S],
+) -> Result<Vec<PathBuf>> {
+    excluded(source, files, excludes, false)
+}
+
+/// Those of the directories `dirs` (relative to `source`) matching none of
+/// `excludes`, like `excluding` does files
+pub fn excluding_dirs<S: AsRef<str>>(
+    source: &Path,
+    dirs: Vec<PathBuf>,
+    excludes: &[S],
+) -> Result<Vec<PathBuf>> {
+    excluded(source, dirs, excludes, true)
+}
+
+fn excluded<S: AsRef<str>>(
+    source: &Path,
+    paths: Vec<PathBuf>,
+    excludes: &[S],
+    is_dir: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut builder = GitignoreBuilder::new(source);
+    for exclude in excludes {
+        builder.add_line(None, exclude.as_ref())?;
+    }
+    let excludes = builder.build()?;
+    Ok(paths
+        .into_iter()
+        .filter(|path| {
+            !excludes
+                .matched_path_or_any_parents(join(source, path), is_dir)
+                .is_ignore()
+        })
+        .collect())
+}
+
+/// Those of `files` with no dotfile or dotdir in their path
+pub fn visible(files: Vec<PathBuf>) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|file| {
+            !file
+                .components()
+                .any(|c| c.as_os_str().as_bytes().starts_with(b"."))
+        })
+        .collect()
+}
+
+/// Those of `files` (relative to `source`) but the symlinks pointing outside
+/// `source`, absolute or climbing above it, which are reported
+pub fn safe_links(source: &Path, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut safe = Vec::new();
+    for file in files {
+        let path = join(source, &file);
+        if path.symlink_metadata()?.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            // how deep below `source` the target gets
+            let mut depth = file.components().count() as isize - 1;
+            let inside = target.is_relative()
+                && target.components().all(|c| {
+                    match c {
+                        Component::ParentDir => depth -= 1,
+                        Component::Normal(_) => depth += 1,
+                        _ => {}
+                    }
+                    depth >= 0
+                });
+            if !inside {
+                output::event("unsafe link, skipped", &[&path, &target]);
+                continue;
+            }
+        }
+        safe.push(file);
+    }
+    Ok(safe)
+}
+
+/// Those of `files` (relative to `source`) of at least `min` and at most `max`
+/// bytes
+pub fn by_size(
+    source: &Path,
+    files: Vec<PathBuf>,
+    min: Option<u64>,
+    max: Option<u64>,
+) -> Result<Vec<PathBuf>> {
+    let mut kept = Vec::new();
+    for file in files {
+        let size = stat(&join(source, &file))?.len();
+        if min.is_none_or(|min| size >= min) && max.is_none_or(|max| size <= max) {
+            kept.push(file);
+        }
+    }
+    Ok(kept)
+}
+
+/// Those of `files` (relative to `source`) modified after `since`
+pub fn by_mtime(source: &Path, files: Vec<PathBuf>, since: SystemTime) -> Result<Vec<PathBuf>> {
+    let mut kept = Vec::new();
+    for file in files {
+        if stat(&join(source, &file))?.modified()? > since {
+            kept.push(file);
+        }
+    }
+    Ok(kept)
+}
+
+/// Metadata of everything under `dest` (or of `dest` itself, keyed by an empty
+/// path), without following symlinks
+pub fn metadata(dest: &Path) -> Result<HashMap<PathBuf, Metadata>> {
+    let mut metadata = HashMap::new();
+    if !dest.exists() {
+        return Ok(metadata);
+    }
+    for entry in WalkBuilder::new(dest)
+        .standard_filters(false)
+        .filter_entry(|entry| !skipped(entry))
+        .build()
+    {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(dest)?.to_path_buf();
+        metadata.insert(path, entry.metadata()?);
+    }
+    Ok(metadata)
+}
+
+/// Groups of `files` whose paths only differ in case, which would overwrite
+/// each other on a case-insensitive file system
+pub fn case_collisions(files: &[PathBuf]) -> Vec<Vec<&PathBuf>> {
+    let mut by_lowercase: HashMap<String, Vec<&PathBuf>> = HashMap::new();
+    for file in files {
+        let lowercase = file.to_string_lossy().to_lowercase();
+        by_lowercase.entry(lowercase).or_default().push(file);
+    }
+    let mut collisions: Vec<Vec<&PathBuf>> = by_lowercase
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect();
+    collisions.sort();
+    collisions
+}
+
+/// Whether the file system of `path`, or of its closest existing ancestor,
+/// ignores case, found by creating a probe file there
+pub fn case_insensitive(path: &Path) -> Result<bool> {
+    let Some(dir) = path.ancestors().find(|dir| dir.is_dir()) else {
+        return Ok(false);
+    };
+    let probe = tempfile::Builder::new()
+        .prefix(".smartscp-case-probe")
+        .tempfile_in(dir)?;
+    let name = probe.path().file_name().unwrap().to_string_lossy();
+    Ok(dir.join(name.to_uppercase()).exists())
+}
+
+/// Fail when one of `files` isn't a plain relative path, which could write
+/// outside the destination, like `../../.bashrc`
+pub fn check_relative(files: &[PathBuf]) -> Result<()> {
+    for file in files {
+        if !file
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("Refuse to copy {file:?}, it leads outside the destination");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_relative_refuses_hostile_names() {
+        for hostile in ["../../.bashrc", "/etc/passwd", "a/../../b", "a/.."] {
+            assert!(
+                check_relative(&[PathBuf::from("fine"), PathBuf::from(hostile)]).is_err(),
+                "{hostile}"
+            );
+        }
+        let fine = [
+            PathBuf::from("a/b"),
+            PathBuf::from(".hidden"),
+            PathBuf::from("..dots"),
+        ];
+        check_relative(&fine).unwrap();
+    }
+
+    #[test]
+    fn tree_lists_files_and_empty_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("full")).unwrap();
+        std::fs::write(root.join("full/file"), "").unwrap();
+        std::fs::create_dir_all(root.join("empty")).unwrap();
+        std::fs::create_dir_all(root.join("nested/empty")).unwrap();
+
+        let (files, empty) = tree(root).unwrap();
+        assert_eq!(files, [PathBuf::from("full/file")]);
+        assert_eq!(
+            empty,
+            [PathBuf::from("empty"), PathBuf::from("nested/empty")]
+        );
+    }
+
+    #[test]
+    fn listed_refuses_hostile_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("a/file"), "").unwrap();
+        let list = dir.path().join("list");
+        for hostile in ["/etc/passwd", "../list", "a/../../list", "a/file\n/tmp"] {
+            std::fs::write(&list, hostile).unwrap();
+            assert!(listed(&root, &list, false).is_err(), "{hostile:?}");
+        }
+
+        std::fs::write(&list, "./a/file\0missing\0").unwrap();
+        assert_eq!(
+            listed(&root, &list, true).unwrap(),
+            [PathBuf::from("a/file")]
+        );
+    }
+
+    #[test]
+    fn by_size_keeps_files_within_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, len) in [("small", 1), ("medium", 10), ("large", 100)] {
+            std::fs::write(dir.path().join(name), vec![b'x'; len]).unwrap();
+        }
+        let files = ["large", "medium", "small"].map(PathBuf::from).to_vec();
+        let kept = by_size(dir.path(), files, Some(5), Some(50)).unwrap();
+        assert_eq!(kept, [PathBuf::from("medium")]);
+    }
+}